@@ -1,55 +1,269 @@
-use crate::context::DeserializeAs;
+use crate::auth::{AuthConfig, Credential, PeerIdentity};
+use crate::context::{ContextStore, DeserializeAs, NodeContextCache};
 use crate::envoy_types::DiscoveryRequest;
-use crate::sources::{InstancesPackage, SourceDest};
+use crate::error::{yaml_excerpt, DiscoveryError};
+use crate::git::GitStatus;
+use crate::health::{HealthMap, ItemHealth};
+use crate::secrets::SecretStore;
+use crate::sources::InstanceIndex;
 use crate::templates::XdsTemplate;
 use axum::body::{Bytes, Full};
 use axum::extract::{Extension, Host, Path};
-use axum::http::StatusCode;
-use axum::response::{IntoResponse, Response};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::Response;
 use axum::Json;
 use dashmap::DashMap;
 use minijinja::{context, Environment, Value as JinjaValue};
+use semver::Version;
 use serde_json::{json, Value as JsonValue};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::watch::Receiver;
-use tracing::info;
+use tracing::{debug, info};
+
+/// Time spent in each stage of answering a request
+pub type Timings = Vec<(&'static str, Duration)>;
 
 #[macro_export]
 macro_rules! measure {
-    ($name:expr, $block:expr) => {{
+    ($timings:expr, $name:expr, $block:expr) => {{
         let start = std::time::Instant::now();
         let result = $block;
-        let duration = start.elapsed();
-        println!("{} ({:?})", $name, duration);
+        $timings.push(($name, start.elapsed()));
         result
     }};
 }
 
+#[derive(Default)]
 pub struct State<'a> {
-    pub instances: Option<Receiver<Vec<InstancesPackage>>>,
+    pub instances: Option<Receiver<Arc<InstanceIndex>>>,
     pub context: Option<Receiver<JinjaValue>>,
-    pub templates: DashMap<String, XdsTemplate>,
+    pub context_status: Option<Arc<ContextStore>>,
+    pub source_status: Option<Arc<HealthMap>>,
+    /// How old loaded data may get before the server stops being ready
+    pub max_staleness: Option<Duration>,
+    pub node_context: Option<NodeContextCache>,
+    pub templates: DashMap<String, Vec<XdsTemplate>>,
     pub env: Environment<'a>,
+    pub auth: Option<AuthConfig>,
+    pub secrets: Option<Receiver<Arc<SecretStore>>>,
+    pub git: Option<Receiver<GitStatus>>,
+    /// Set on shutdown, failing readiness while requests are still served
+    pub draining: AtomicBool,
+}
+
+/// The resource type served from the secret store rather than a template
+const SECRETS: &str = "secrets";
+
+/// Groups templates by resource type, as `State` expects them
+pub fn templates_by_resource_type(templates: &[XdsTemplate]) -> DashMap<String, Vec<XdsTemplate>> {
+    let grouped: DashMap<String, Vec<XdsTemplate>> = DashMap::new();
+    for template in templates.iter() {
+        grouped
+            .entry(template.resource_type.clone())
+            .or_default()
+            .push(template.clone());
+    }
+    grouped
+}
+
+/// The pieces of a request that decide which template renders it
+pub struct Selection {
+    pub resource_type: String,
+    pub version: Version,
+    pub template: XdsTemplate,
 }
 
 impl<'a> State<'a> {
-    fn template(&'a self, envoy_version: &str, resource_type: &str) -> Option<XdsTemplate> {
-        // Incrementally walk the semantic version to find a template
-        let mut octets = envoy_version.split('.').collect::<Vec<_>>();
-        while !octets.is_empty() {
-            let prefix = octets.join(".");
-            let name = format!("{}/{}", prefix, resource_type);
-            if let Some(template) = self.templates.get(&name) {
-                return Some(template.clone());
+    fn template(
+        &'a self,
+        request: &DiscoveryRequest,
+        host_header: &str,
+        envoy_version: &Version,
+        resource_type: &str,
+    ) -> Option<XdsTemplate> {
+        let templates = self.templates.get(resource_type)?;
+        let mut selected: Option<&XdsTemplate> = None;
+        for template in templates.iter() {
+            if !template.envoy_version.matches(envoy_version)
+                || !template.selector.matches(request, host_header)
+            {
+                continue;
+            }
+            if selected.is_none_or(|s| template.preferred_over(s)) {
+                selected = Some(template);
+            }
+        }
+        selected.cloned()
+    }
+
+    /// Rejects requests without a credential for the node, when auth is configured,
+    /// returning the credential presented
+    pub fn authorize(
+        &'a self,
+        headers: &HeaderMap,
+        peer: Option<&PeerIdentity>,
+        payload: &DiscoveryRequest,
+    ) -> Result<Option<&'a Credential>, DiscoveryError> {
+        match &self.auth {
+            Some(auth) => auth.authorize(headers, peer, payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The git commit being served, if templates are read from a repository
+    pub fn commit(&'a self) -> Option<String> {
+        self.git.as_ref().map(|git| git.borrow().commit.clone())
+    }
+
+    /// Whether every background poller is still running. Each one owns the sender of its channel,
+    /// except context pollers, which the store tracks.
+    pub fn live(&'a self) -> bool {
+        let running = |closed: Option<bool>| !closed.unwrap_or(false);
+        running(self.instances.as_ref().map(|rx| rx.has_changed().is_err()))
+            && running(self.secrets.as_ref().map(|rx| rx.has_changed().is_err()))
+            && running(self.git.as_ref().map(|rx| rx.has_changed().is_err()))
+            && running(self.context_status.as_ref().map(|store| !store.running()))
+    }
+
+    /// The secrets for the node, when it asks for secrets and they are configured.
+    /// They are only sent to authenticated clients.
+    pub fn secrets(
+        &'a self,
+        resource: &str,
+        payload: &DiscoveryRequest,
+        credential: Option<&Credential>,
+        redact: bool,
+    ) -> Result<Option<JsonValue>, DiscoveryError> {
+        let Some(store) = self.secrets.as_ref() else {
+            return Ok(None);
+        };
+        if resource.split_once(':').map(|(_, t)| t) != Some(SECRETS) {
+            return Ok(None);
+        }
+        // The credential has already been checked against the node's cluster by `authorize`
+        if credential.is_none() {
+            return Err(DiscoveryError::Unauthenticated);
+        }
+        Ok(Some(store.borrow().resources(payload, redact)))
+    }
+
+    pub fn select(
+        &'a self,
+        resource: &str,
+        payload: &DiscoveryRequest,
+        host_header: &str,
+    ) -> Result<Selection, DiscoveryError> {
+        let resource_type = match resource.split_once(':') {
+            Some((_, resource_type)) => resource_type.to_string(),
+            None => {
+                return Err(DiscoveryError::InvalidResource {
+                    resource: resource.to_string(),
+                })
+            }
+        };
+        let version = payload
+            .envoy_version()
+            .map_err(|e| DiscoveryError::InvalidVersion {
+                resource_type: resource_type.clone(),
+                message: e.to_string(),
+            })?;
+        match self.template(payload, host_header, &version, &resource_type) {
+            Some(template) => Ok(Selection {
+                resource_type,
+                version,
+                template,
+            }),
+            None => Err(DiscoveryError::TemplateNotFound {
+                resource_type,
+                version: version.to_string(),
+            }),
+        }
+    }
+
+    /// Everything a template is rendered with
+    pub async fn context(
+        &'a self,
+        payload: &DiscoveryRequest,
+        host_header: &str,
+        peer: Option<&PeerIdentity>,
+        timings: &mut Timings,
+    ) -> JinjaValue {
+        let mut i = JinjaValue::from(Vec::<JinjaValue>::new());
+        if let Some(sources) = &self.instances {
+            let index = measure!(timings, "sources", { sources.borrow().clone() });
+            i = measure!(timings, "filtering", { index.instances(payload) });
+        }
+
+        let mut ctx = minijinja::context! {};
+        if let Some(c) = &self.context {
+            ctx = c.borrow().clone();
+        }
+
+        let mut node_ctx = HashMap::new();
+        if let Some(cache) = &self.node_context {
+            node_ctx = measure!(timings, "node_context", { cache.values(payload).await });
+        }
+
+        // Per-node items shadow global items of the same name
+        context! {
+            instances => i,
+            host_header => host_header,
+            peer => peer,
+            discovery_request => payload,
+            ..context! { ..JinjaValue::from(node_ctx), ..ctx }
+        }
+    }
+
+    pub fn render(
+        &'a self,
+        selection: &Selection,
+        context: JinjaValue,
+    ) -> Result<String, DiscoveryError> {
+        let template = &selection.template;
+        let render_error = |message: String| DiscoveryError::Render {
+            template: template.name(),
+            resource_type: selection.resource_type.clone(),
+            message,
+        };
+        match template.call_python {
+            Some(true) => template
+                .call(context)
+                .map_err(|e| render_error(e.to_string())),
+            _ => {
+                let template_string =
+                    template
+                        .source()
+                        .map_err(|e| DiscoveryError::TemplateSource {
+                            template: template.name(),
+                            resource_type: selection.resource_type.clone(),
+                            message: e.to_string(),
+                        })?;
+                self.env
+                    .render_str(template_string.as_str(), context)
+                    .map_err(|e| render_error(e.to_string()))
             }
-            octets.pop();
         }
-        // Try the default template
-        let name = format!("default/{}", resource_type);
-        if let Some(template) = self.templates.get(&name) {
-            return Some(template.clone());
+    }
+}
+
+/// The rendered resources as a JSON list
+pub fn resources(selection: &Selection, text: &str) -> Result<String, DiscoveryError> {
+    match selection.template.deserialize_as {
+        DeserializeAs::Yaml => {
+            let y: JsonValue =
+                serde_yaml::from_str(text).map_err(|e| DiscoveryError::Deserialize {
+                    template: selection.template.name(),
+                    resource_type: selection.resource_type.clone(),
+                    message: e.to_string(),
+                    excerpt: yaml_excerpt(&e, text),
+                })?;
+            Ok(y.to_string())
         }
-        None
+        // JSON / Plaintext are chucked straight in
+        _ => Ok(text.to_string()),
     }
 }
 
@@ -58,160 +272,430 @@ pub async fn discovery(
     Json(payload): Json<DiscoveryRequest>,
     Extension(state): Extension<Arc<State<'_>>>,
     Host(host_header): Host,
-) -> Result<Response<Full<Bytes>>, impl IntoResponse> {
-    let (_, resource_type) = resource.split_once(':').unwrap();
-    let version = measure!("envoy version", { payload.envoy_version() });
-    let templ = measure!("template", { state.template(&version, resource_type) });
-    let service_cluster = payload.cluster();
+    headers: HeaderMap,
+    peer: Option<Extension<PeerIdentity>>,
+) -> Result<Response<Full<Bytes>>, DiscoveryError> {
+    let mut timings = Timings::new();
+    let credential = measure!(timings, "auth", {
+        state.authorize(&headers, peer.as_deref(), &payload)?
+    });
+
+    if let Some(secrets) = state.secrets(&resource, &payload, credential, false)? {
+        info!(
+            resource_type = SECRETS,
+            resource_names = ?payload.resource_names(),
+            service_cluster = %payload.cluster(),
+            api_version = %api_version,
+        );
+        let res = secrets.to_string();
+        let version_info = xxhash_rust::xxh64::xxh64(res.as_bytes(), 0).to_string();
+        if version_info == payload.version_info.unwrap_or("0".to_string()) {
+            return Ok(not_modified());
+        }
+        return Ok(resources_response(&version_info, &res, state.commit()));
+    }
+
+    let selection = measure!(timings, "template", {
+        state.select(&resource, &payload, &host_header)?
+    });
 
     info!(
-        resource_type = %resource_type,
+        resource_type = %selection.resource_type,
         resource_names = ?payload.resource_names(),
-        service_cluster = %service_cluster,
+        service_cluster = %payload.cluster(),
         api_version = %api_version,
-        version = %version,
+        version = %selection.version,
     );
 
-    if let Some(template) = templ {
-        let mut i = json! {[]};
-        let borrow = i.as_array_mut().unwrap();
-
-        if let Some(sources) = &state.instances {
-            let instances = measure!("sources", { sources.borrow().clone() });
-            measure!("filtering", {
-                instances
-                    .into_iter()
-                    .filter(|instance| match &instance.dest {
-                        SourceDest::Match(val) => val == service_cluster,
-                        SourceDest::Any => true,
-                    })
-                    .for_each(|instance| {
-                        if let Some(instances) = instance.instances.as_array() {
-                            borrow.extend(instances.clone())
-                        }
-                    })
-            });
-        }
+    let context = state
+        .context(&payload, &host_header, peer.as_deref(), &mut timings)
+        .await;
+    let text = measure!(timings, "render", { state.render(&selection, context)? });
 
-        let mut ctx = minijinja::context! {};
-        if let Some(c) = &state.context {
-            ctx = c.borrow().clone();
-        }
+    let hash = measure!(
+        timings,
+        "hashing",
+        xxhash_rust::xxh64::xxh64(text.as_bytes(), 0)
+    );
+    let version_info = hash.to_string();
+    if version_info == payload.version_info.unwrap_or("0".to_string()) {
+        debug!(timings = ?timings);
+        return Ok(not_modified());
+    }
 
-        let text = measure!(
-            "render",
-            match template.call_python {
-                Some(true) => template
-                    .call(context! {
-                            instances => i,
-                            host_header => host_header,
-                            discovery_request => payload,
-                            ..ctx
-                    })
-                    .unwrap(),
-                _ => {
-                    let template_string = template.source().unwrap();
-                    let result = state.env.render_str(
-                        template_string.as_str(),
-                        context! {
-                            instances => i,
-                            host_header => host_header,
-                            discovery_request => payload,
-                            ..ctx
-                        },
-                    );
-                    match result {
-                        Ok(text) => text,
-                        Err(e) => {
-                            return Err(Response::builder()
-                                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                .body(format!("{e}"))
-                                .unwrap());
-                        }
-                    }
-                }
-            }
-        );
+    let res = measure!(timings, "deser", { resources(&selection, &text)? });
+    debug!(timings = ?timings);
+    Ok(resources_response(&version_info, &res, state.commit()))
+}
 
-        let hash = measure!("hashing", xxhash_rust::xxh64::xxh64(text.as_bytes(), 0));
-        if hash.to_string() == payload.version_info.unwrap_or("0".to_string()) {
-            return Ok(Response::builder()
-                .status(StatusCode::NOT_MODIFIED)
-                .body(Full::from(""))
-                .unwrap());
-        }
+fn not_modified() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Full::from(""))
+        .unwrap()
+}
 
-        let response = measure!(
-            "deser",
-            match template.deserialize_as {
-                DeserializeAs::Yaml => {
-                    let y: JsonValue = match serde_yaml::from_str(&text) {
-                        Ok(yombl) => yombl,
-                        Err(e) => {
-                            intercept_yaml_error(&e.to_string(), &text);
-                            panic!("{e}");
-                        }
-                    };
-                    let res = serde_json::to_string(&y).unwrap();
-                    format!("{{\"version_info\": \"{hash}\", \"resources\": {res}}}")
-                }
-                // JSON / Plaintext are chucked straight in
-                _ => {
-                    format!("{{\"version_info\": \"{hash}\", \"resources\": {text}}}")
-                }
-            }
-        );
+/// The response body, naming the commit it was rendered from when serving from git.
+/// `version_info` only changes with the output, so a commit that changes nothing isn't pushed.
+fn resources_response(
+    version_info: &str,
+    res: &str,
+    commit: Option<String>,
+) -> Response<Full<Bytes>> {
+    let control_plane = match commit {
+        Some(commit) => format!(", \"control_plane\": {}", json!({ "identifier": commit })),
+        None => String::new(),
+    };
+    let response =
+        format!("{{\"version_info\": \"{version_info}\", \"resources\": {res}{control_plane}}}");
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Full::from(response))
+        .unwrap()
+}
 
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(Full::from(response))
-            .unwrap())
+/// Renders a request like `discovery` does, returning the output of every stage
+pub async fn debug_render(
+    Path((_, resource)): Path<(String, String)>,
+    Json(payload): Json<DiscoveryRequest>,
+    Extension(state): Extension<Arc<State<'_>>>,
+    Host(host_header): Host,
+    headers: HeaderMap,
+    peer: Option<Extension<PeerIdentity>>,
+) -> Result<Json<JsonValue>, DiscoveryError> {
+    let mut timings = Timings::new();
+    let credential = measure!(timings, "auth", {
+        state.authorize(&headers, peer.as_deref(), &payload)?
+    });
+    // Secret values never leave through the debug endpoint
+    if let Some(secrets) = state.secrets(&resource, &payload, credential, true)? {
+        return Ok(Json(json!({ "resources": secrets })));
+    }
+    let selection = measure!(timings, "template", {
+        state.select(&resource, &payload, &host_header)?
+    });
+    let context = state
+        .context(&payload, &host_header, peer.as_deref(), &mut timings)
+        .await;
+
+    let mut body = json!({
+        "template": {
+            "name": selection.template.name(),
+            "path": selection.template.path(),
+        },
+        "context": context,
+    });
+    let rendered = measure!(timings, "render", { state.render(&selection, context) });
+    let outcome = rendered.and_then(|text| {
+        body["rendered"] = json!(text);
+        let res = measure!(timings, "deser", { resources(&selection, &text)? });
+        body["resources"] = serde_json::from_str(&res).unwrap_or(JsonValue::String(res));
+        Ok(())
+    });
+    if let Err(e) = outcome {
+        body["error"] = e.body();
+    }
+    body["timings"] = timings
+        .iter()
+        .map(|(stage, duration)| json!({"stage": stage, "micros": duration.as_micros() as u64}))
+        .collect();
+    Ok(Json(body))
+}
+
+/// The health of each template context item
+pub async fn context_status(Extension(state): Extension<Arc<State<'_>>>) -> Json<JsonValue> {
+    let health = state
+        .context_status
+        .as_ref()
+        .map(|store| store.health())
+        .unwrap_or_default();
+    Json(json!(health))
+}
+
+/// The git commit being served, if templates are read from a repository
+pub async fn git_status(Extension(state): Extension<Arc<State<'_>>>) -> Json<JsonValue> {
+    let status = state.git.as_ref().map(|git| git.borrow().clone());
+    Json(json!(status))
+}
+
+/// Fails once a background poller has stopped, so that the server gets restarted
+pub async fn liveness(Extension(state): Extension<Arc<State<'_>>>) -> Result<String, StatusCode> {
+    if state.live() {
+        Ok("OK".to_string())
     } else {
-        Err(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(format!(
-                "No configuration found for {resource_type}:{version}. Full list: {:?}",
-                state
-                    .templates
-                    .iter()
-                    .map(|i| i.key().to_string())
-                    .collect::<Vec<String>>()
-            ))
-            .unwrap())
-    }
-}
-
-fn intercept_yaml_error(message: &str, content: &str) {
-    if let Some(line_start) = message.find("line ") {
-        if let Some(column_start) = message.find("column ") {
-            let line_str = &message[line_start + 5..column_start - 1];
-            let column_str = &message[column_start + 7..];
-
-            let line: usize = line_str.parse().unwrap();
-            let column: usize = column_str.parse().unwrap();
-
-            let start = if line >= 5 { line - 5 } else { 1 };
-            let end = line + 5;
-
-            let lines = content
-                .split('\n')
-                .enumerate()
-                // Start index from 1
-                .map(|(i, txt)| (i + 1, txt));
-
-            for (idx, text) in lines {
-                if idx >= start && idx <= end {
-                    println!("{}: {}", idx, text);
-                    if idx == line {
-                        println!("{}^", " ".repeat(column + 3));
-                    }
-                }
-            }
-        }
+        Err(StatusCode::SERVICE_UNAVAILABLE)
     }
 }
 
+/// Fails until every source and context item has loaded, and once any are too stale
+pub async fn readiness(
+    Extension(state): Extension<Arc<State<'_>>>,
+) -> (StatusCode, Json<JsonValue>) {
+    readiness_report(&state, false)
+}
+
+/// Readiness along with each item's failures, for listeners serving admin routes
+pub async fn detailed_readiness(
+    Extension(state): Extension<Arc<State<'_>>>,
+) -> (StatusCode, Json<JsonValue>) {
+    readiness_report(&state, true)
+}
+
+fn readiness_report(state: &State, detailed: bool) -> (StatusCode, Json<JsonValue>) {
+    let draining = state.draining.load(Ordering::Relaxed);
+    let mut ready = !draining;
+    let mut status = |items: BTreeMap<String, ItemHealth>| -> JsonValue {
+        items
+            .into_iter()
+            .map(|(name, health)| {
+                let item_ready = health.ready(state.max_staleness);
+                ready &= item_ready;
+                // Errors can describe internal systems, so they stay off public listeners
+                let mut item = match detailed {
+                    true => json!(health),
+                    false => json!({
+                        "healthy": health.healthy,
+                        "last_success": health.last_success,
+                    }),
+                };
+                item["ready"] = json!(item_ready);
+                (name, item)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    };
+    let sources = status(
+        state
+            .source_status
+            .as_ref()
+            .map(|health| health.snapshot())
+            .unwrap_or_default(),
+    );
+    let context = status(
+        state
+            .context_status
+            .as_ref()
+            .map(|store| store.health())
+            .unwrap_or_default(),
+    );
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "ready": ready,
+        "draining": draining,
+        "sources": sources,
+        "context": context,
+    });
+    (code, Json(body))
+}
+
 pub async fn healthcheck() -> String {
     "OK".to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(response: Response<Full<Bytes>>) -> JsonValue {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn state(templates: JsonValue) -> State<'static> {
+        let templates: Vec<XdsTemplate> = serde_json::from_value(templates).unwrap();
+        State {
+            templates: templates_by_resource_type(&templates),
+            ..Default::default()
+        }
+    }
+
+    /// The path of the clusters template selected for a node with the role and host header
+    fn selected(state: &State, role: &str, host_header: &str) -> Option<String> {
+        let request = DiscoveryRequest::new("edge".into(), "1.25.3".into(), None)
+            .with_metadata(HashMap::from([("role".to_string(), json!(role))]));
+        let version = Version::parse("1.25.3").unwrap();
+        let template = state.template(&request, host_header, &version, "clusters")?;
+        Some(template.path().display().to_string())
+    }
+
+    fn template(path: &str, envoy_version: &str, priority: i32, selector: JsonValue) -> JsonValue {
+        json!({
+            "path": path,
+            "envoy_version": envoy_version,
+            "resource_type": "clusters",
+            "priority": priority,
+            "selector": selector,
+        })
+    }
+
+    #[test]
+    fn the_highest_priority_match_is_selected() {
+        let state = state(json!([
+            template(
+                "low.yaml",
+                "default",
+                0,
+                json!({"node": {"metadata.role": "gateway"}})
+            ),
+            template(
+                "high.yaml",
+                "default",
+                10,
+                json!({"host_header": "*.internal"})
+            ),
+            template(
+                "other.yaml",
+                "default",
+                20,
+                json!({"host_header": "*.example.com"})
+            ),
+        ]));
+        assert_eq!(
+            selected(&state, "gateway", "xds.internal").as_deref(),
+            Some("high.yaml")
+        );
+    }
+
+    #[test]
+    fn ties_go_to_the_narrowest_version_then_the_first_listed() {
+        let state = state(json!([
+            template("default.yaml", "default", 0, json!({})),
+            template("first.yaml", "1.25", 0, json!({})),
+            template("second.yaml", "1.25", 0, json!({})),
+        ]));
+        assert_eq!(
+            selected(&state, "gateway", "").as_deref(),
+            Some("first.yaml")
+        );
+    }
+
+    #[test]
+    fn host_headers_and_node_metadata_select_separately() {
+        let state = state(json!([
+            template(
+                "host.yaml",
+                "default",
+                1,
+                json!({"host_header": "*.internal"})
+            ),
+            template(
+                "node.yaml",
+                "default",
+                1,
+                json!({"node": {"metadata.role": "gateway"}})
+            ),
+        ]));
+        assert_eq!(
+            selected(&state, "sidecar", "xds.internal").as_deref(),
+            Some("host.yaml")
+        );
+        assert_eq!(
+            selected(&state, "gateway", "xds.example.com").as_deref(),
+            Some("node.yaml")
+        );
+        // When both match, the first listed wins the tie
+        assert_eq!(
+            selected(&state, "gateway", "xds.internal").as_deref(),
+            Some("host.yaml")
+        );
+        assert_eq!(selected(&state, "sidecar", "xds.example.com"), None);
+    }
+
+    #[test]
+    fn templates_without_selectors_are_the_fallback() {
+        let state = state(json!([
+            template("fallback.yaml", "1.25", 0, json!({})),
+            template(
+                "gateway.yaml",
+                "1.25",
+                5,
+                json!({"node": {"metadata.role": "gateway"}})
+            ),
+            template("old.yaml", "1.24", 10, json!({})),
+        ]));
+        assert_eq!(
+            selected(&state, "gateway", "").as_deref(),
+            Some("gateway.yaml")
+        );
+        assert_eq!(
+            selected(&state, "sidecar", "").as_deref(),
+            Some("fallback.yaml")
+        );
+    }
+
+    /// Renders a clusters template through the debug endpoint
+    async fn debug(source: &str) -> JsonValue {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clusters.yaml.jinja2");
+        std::fs::write(&path, source).unwrap();
+        let state = state(json!([{
+            "path": path,
+            "envoy_version": "default",
+            "resource_type": "clusters",
+            "deserialize_as": "yaml",
+        }]));
+        let Json(body) = debug_render(
+            Path(("v3".into(), "discovery:clusters".into())),
+            Json(DiscoveryRequest::new("edge".into(), "1.25.0".into(), None)),
+            Extension(Arc::new(state)),
+            Host("localhost".into()),
+            HeaderMap::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        body
+    }
+
+    #[tokio::test]
+    async fn debug_render_shows_every_stage() {
+        let body = debug("- name: {{ discovery_request.node.cluster }}\n").await;
+        assert_eq!(body["template"]["name"], "default/clusters");
+        assert_eq!(
+            body["context"]["discovery_request"]["node"]["cluster"],
+            "edge"
+        );
+        assert_eq!(body["rendered"], "- name: edge");
+        assert_eq!(body["resources"], json!([{"name": "edge"}]));
+        assert!(body.get("error").is_none());
+        let stages: Vec<&str> = body["timings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["stage"].as_str().unwrap())
+            .collect();
+        assert!(stages.contains(&"render"), "{stages:?}");
+    }
+
+    #[tokio::test]
+    async fn debug_render_reports_render_errors_in_the_body() {
+        let body = debug("- name: {% if %}\n").await;
+        assert_eq!(body["error"]["code"], "render");
+        assert_eq!(body["error"]["template"], "default/clusters");
+        assert_eq!(
+            body["context"]["discovery_request"]["node"]["cluster"],
+            "edge"
+        );
+        assert!(body.get("rendered").is_none());
+        assert!(body.get("resources").is_none());
+    }
+
+    #[tokio::test]
+    async fn responses_name_the_commit_they_were_rendered_from() {
+        let response = resources_response("42", "[]", Some("abc123".to_string()));
+        assert_eq!(
+            body(response).await,
+            json!({"version_info": "42", "resources": [], "control_plane": {"identifier": "abc123"}})
+        );
+        let response = resources_response("42", "[]", None);
+        assert_eq!(
+            body(response).await,
+            json!({"version_info": "42", "resources": []})
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::envoy_types::DiscoveryRequest;
+use crate::error::DiscoveryError;
+use crate::matching::{GlobPattern, NodeKey, NodeMatching};
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// The identity of the client at the other end of the connection, from its certificate
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PeerIdentity {
+    /// Subject alternative names, e.g. `DNS:envoy.internal` or `URI:spiffe://mesh/frontend`
+    pub sans: Vec<String>,
+}
+
+/// Splits on `separator` where it isn't inside double quotes, leaving the quotes in place
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Removes surrounding double quotes and the backslashes escaping characters within them
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        unquoted.push(if c == '\\' {
+            chars.next().unwrap_or(c)
+        } else {
+            c
+        });
+    }
+    unquoted
+}
+
+impl PeerIdentity {
+    /// Reads the last hop of an `x-forwarded-client-cert` header, as set by a fronting Envoy.
+    /// Values may be quoted, e.g. `Subject="CN=front,O=Mesh"`, to hold `,` `;` or `=`.
+    pub fn from_forwarded(header: &str) -> Self {
+        let last = split_unquoted(header, ',').pop().unwrap_or_default();
+        let sans = split_unquoted(last, ';')
+            .into_iter()
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(key, _)| matches!(key.trim(), "URI" | "DNS"))
+            .map(|(key, value)| format!("{}:{}", key.trim(), unquote(value)))
+            .collect();
+        Self { sans }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Method {
+    /// `Authorization: Bearer <token>`
+    Bearer { token: String },
+    /// A hex HMAC-SHA256 of the node id, sent in the node's metadata
+    Hmac {
+        key: String,
+        #[serde(default = "default_signature_key")]
+        metadata_key: String,
+    },
+    /// A client certificate with a SAN matching the pattern, e.g. `URI:spiffe://mesh/*`
+    ClientCert { san: GlobPattern },
+}
+
+fn default_signature_key() -> String {
+    "auth_signature".to_string()
+}
+
+/// A way of authenticating, and the nodes a client using it may request for
+#[derive(Deserialize, Clone, Debug)]
+pub struct Credential {
+    #[serde(flatten)]
+    pub method: Method,
+    pub clusters: Vec<GlobPattern>,
+    /// Glob patterns keyed by node attribute, e.g. `id` or `metadata.team`, which must all match.
+    /// Every attribute `node_matching` reads besides the cluster needs one.
+    #[serde(default)]
+    pub node: HashMap<NodeKey, GlobPattern>,
+}
+
+impl Credential {
+    /// Whether the credential may request configuration for the node that sent `request`
+    pub fn allows(&self, request: &DiscoveryRequest) -> bool {
+        let cluster = self
+            .clusters
+            .iter()
+            .any(|pattern| pattern.matches(request.cluster()));
+        cluster
+            && self.node.iter().all(|(key, pattern)| {
+                key.value(request)
+                    .is_some_and(|value| pattern.matches(&value))
+            })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+    pub credentials: Vec<Credential>,
+    /// Read client certificates from `x-forwarded-client-cert` when TLS ends at a proxy
+    #[serde(default)]
+    pub trust_forwarded_client_cert: bool,
+}
+
+impl Method {
+    fn presented(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<&PeerIdentity>,
+        request: &DiscoveryRequest,
+    ) -> bool {
+        match self {
+            Method::Bearer { token } => {
+                let presented = headers
+                    .get("authorization")
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.strip_prefix("Bearer "));
+                // Comparing digests keeps the comparison time independent of the token
+                presented.is_some_and(|p| Sha256::digest(p) == Sha256::digest(token))
+            }
+            Method::Hmac { key, metadata_key } => {
+                let signature = request
+                    .metadata()
+                    .get(metadata_key)
+                    .and_then(|s| s.as_str())
+                    .and_then(|s| hex::decode(s).ok());
+                let (Some(id), Some(signature)) = (request.node_id(), signature) else {
+                    return false;
+                };
+                let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(key.as_bytes()) else {
+                    return false;
+                };
+                mac.update(id.as_bytes());
+                mac.verify_slice(&signature).is_ok()
+            }
+            Method::ClientCert { san } => {
+                peer.is_some_and(|peer| peer.sans.iter().any(|s| san.matches(s)))
+            }
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Checks every node attribute instances are matched on is restricted by each credential,
+    /// since a client could otherwise claim another node's attributes to get its instances
+    pub fn check(&self, matching: &NodeMatching) -> Result<(), String> {
+        for rule in matching.rules() {
+            if rule.node_key == NodeKey::Cluster {
+                continue;
+            }
+            if let Some(i) = self
+                .credentials
+                .iter()
+                .position(|c| !c.node.contains_key(&rule.node_key))
+            {
+                return Err(format!(
+                    "auth.credentials[{i}] needs a node pattern for {}, which node_matching reads",
+                    rule.node_key
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds a credential the request carries that may fetch configuration for its node
+    pub fn authorize(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<&PeerIdentity>,
+        request: &DiscoveryRequest,
+    ) -> Result<&Credential, DiscoveryError> {
+        let forwarded = match (self.trust_forwarded_client_cert, peer) {
+            (true, None) => headers
+                .get("x-forwarded-client-cert")
+                .and_then(|h| h.to_str().ok())
+                .map(PeerIdentity::from_forwarded),
+            _ => None,
+        };
+        let peer = peer.or(forwarded.as_ref());
+
+        let mut authenticated = false;
+        for credential in self.credentials.iter() {
+            if !credential.method.presented(headers, peer, request) {
+                continue;
+            }
+            authenticated = true;
+            if credential.allows(request) {
+                return Ok(credential);
+            }
+        }
+        if authenticated {
+            Err(DiscoveryError::Forbidden {
+                cluster: request.cluster().to_string(),
+            })
+        } else {
+            Err(DiscoveryError::Unauthenticated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn auth(config: serde_json::Value) -> AuthConfig {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn request(cluster: &str, id: &str, metadata: serde_json::Value) -> DiscoveryRequest {
+        DiscoveryRequest::new(cluster.to_string(), "1.25.0".to_string(), None)
+            .with_node_id(id.to_string())
+            .with_metadata(serde_json::from_value(metadata).unwrap())
+    }
+
+    fn sign(key: &str, id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(id.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn outcome(result: Result<&Credential, DiscoveryError>) -> &'static str {
+        match result {
+            Ok(_) => "ok",
+            Err(e) => e.code(),
+        }
+    }
+
+    #[test]
+    fn hmac_signatures_cover_the_node_id() {
+        let auth = auth(json!({"credentials": [{"type": "hmac", "key": "k", "clusters": ["*"]}]}));
+        let check = |id: &str, signature: String| {
+            let request = request("c", id, json!({"auth_signature": signature}));
+            outcome(auth.authorize(&HeaderMap::new(), None, &request))
+        };
+        assert_eq!(check("node-1", sign("k", "node-1")), "ok");
+        assert_eq!(check("node-2", sign("k", "node-1")), "unauthenticated");
+        assert_eq!(check("node-1", sign("other", "node-1")), "unauthenticated");
+        assert_eq!(check("node-1", "not hex".to_string()), "unauthenticated");
+    }
+
+    #[test]
+    fn credentials_restrict_clusters_and_node_attributes() {
+        let auth = auth(json!({"credentials": [{
+            "type": "bearer",
+            "token": "t",
+            "clusters": ["front*"],
+            "node": {"metadata.team": "web"},
+        }]}));
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer t".parse().unwrap());
+        let check = |cluster, team| {
+            let request = request(cluster, "n", json!({"team": team}));
+            outcome(auth.authorize(&headers, None, &request))
+        };
+        assert_eq!(check("frontend", "web"), "ok");
+        assert_eq!(check("frontend", "payments"), "forbidden");
+        assert_eq!(check("backend", "web"), "forbidden");
+        let request = request("frontend", "n", json!({"team": "web"}));
+        assert_eq!(
+            outcome(auth.authorize(&HeaderMap::new(), None, &request)),
+            "unauthenticated"
+        );
+    }
+
+    #[test]
+    fn matched_node_attributes_must_be_bound() {
+        let matching: NodeMatching = serde_json::from_value(json!({"all": [
+            {"source_key": "service_clusters"},
+            {"source_key": "ids", "node_key": "id"},
+        ]}))
+        .unwrap();
+        let unbound =
+            auth(json!({"credentials": [{"type": "bearer", "token": "t", "clusters": ["*"]}]}));
+        assert!(unbound.check(&matching).is_err());
+        let bound = auth(json!({"credentials": [
+            {"type": "bearer", "token": "t", "clusters": ["*"], "node": {"id": "*"}}
+        ]}));
+        assert!(bound.check(&matching).is_ok());
+    }
+
+    #[test]
+    fn forwarded_certs_read_the_last_hop() {
+        let header = concat!(
+            r#"By=spiffe://mesh/proxy;URI=spiffe://mesh/first,"#,
+            r#"By=spiffe://mesh/proxy;Subject="CN=front,O=Mesh;Inc";"#,
+            r#"URI=spiffe://mesh/frontend;DNS=front.internal;DNS="a\"b""#
+        );
+        assert_eq!(
+            PeerIdentity::from_forwarded(header).sans,
+            [
+                "URI:spiffe://mesh/frontend",
+                "DNS:front.internal",
+                r#"DNS:a"b"#
+            ]
+        );
+        assert!(PeerIdentity::from_forwarded("").sans.is_empty());
+    }
+}
@@ -1,13 +1,40 @@
-use clap::Parser;
-use reqwest::Client;
-use sovereign_rs::envoy_types::DiscoveryRequest;
+use clap::{Parser, ValueEnum};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Client, Identity, StatusCode};
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+use similar::TextDiff;
+use sovereign_rs::envoy_types::{DiscoveryRequest, Locality};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::time::{sleep, Duration};
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Output {
+    Json,
+    Yaml,
+    /// Only the name of each resource
+    Names,
+}
+
+/// Sends discovery requests the way Envoy would, for debugging a control plane.
+///
+/// Only the JSON-REST API is supported, since the server does not serve gRPC/ADS yet.
 #[derive(Parser, Debug)]
 struct Args {
+    #[arg(long, default_value = "http://localhost:8080", env = "SOVEREIGN_URL")]
+    url: String,
+
+    #[arg(long, default_value = "v3")]
+    api_version: String,
+
     #[arg(long, default_value = "clusters")]
     resource_type: String,
 
-    #[arg(long, default_value = "commercial_development_customer_shared_1")]
+    /// The cluster of the node, which decides the instances and templates it is served
+    #[arg(long, env = "SOVEREIGN_SERVICE_CLUSTER")]
     service_cluster: String,
 
     #[arg(long, value_delimiter = ',')]
@@ -15,27 +42,230 @@ struct Args {
 
     #[arg(long, default_value = "1.25.0")]
     envoy_version: String,
+
+    #[arg(long)]
+    node_id: Option<String>,
+
+    #[arg(long)]
+    region: Option<String>,
+
+    #[arg(long)]
+    zone: Option<String>,
+
+    #[arg(long)]
+    sub_zone: Option<String>,
+
+    /// Node metadata as `key=value`, where values are parsed as JSON if possible
+    #[arg(long, value_parser = parse_metadata)]
+    metadata: Vec<(String, JsonValue)>,
+
+    /// The version the node already has, which the server answers with a 304 if unchanged
+    #[arg(long)]
+    version_info: Option<String>,
+
+    #[arg(long, value_enum, default_value = "json")]
+    output: Output,
+
+    /// Keep polling, passing along the last version_info and printing what changed
+    #[arg(long)]
+    watch: bool,
+
+    /// Seconds between polls in watch mode
+    #[arg(long, default_value_t = 5)]
+    interval: u64,
+
+    /// Sent as `Authorization: Bearer <token>`
+    #[arg(long, env = "SOVEREIGN_TOKEN")]
+    token: Option<String>,
+
+    /// Signs the node id with HMAC-SHA256, sent in the node's metadata
+    #[arg(long, env = "SOVEREIGN_HMAC_KEY", requires = "node_id")]
+    hmac_key: Option<String>,
+
+    /// The metadata key the HMAC signature is sent under
+    #[arg(long, default_value = "auth_signature")]
+    hmac_metadata_key: String,
+
+    /// PEM client certificate, for servers that require one
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM PKCS#8 key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// PEM bundle of CAs to trust for the server's certificate, besides the system ones
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+}
+
+fn parse_metadata(s: &str) -> Result<(String, JsonValue), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=value, got {s}"))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| JsonValue::from(value));
+    Ok((key.to_string(), value))
+}
+
+impl Args {
+    fn metadata(&self) -> HashMap<String, JsonValue> {
+        let mut metadata: HashMap<_, _> = self.metadata.iter().cloned().collect();
+        if let (Some(key), Some(id)) = (&self.hmac_key, &self.node_id) {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+            mac.update(id.as_bytes());
+            let signature = hex::encode(mac.finalize().into_bytes());
+            metadata.insert(self.hmac_metadata_key.clone(), JsonValue::from(signature));
+        }
+        metadata
+    }
+
+    fn client(&self) -> anyhow::Result<Client> {
+        let mut builder = Client::builder();
+        if let Some(token) = &self.token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))?;
+            value.set_sensitive(true);
+            builder = builder.default_headers(HeaderMap::from_iter([(AUTHORIZATION, value)]));
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let identity = Identity::from_pkcs8_pem(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+            builder = builder.identity(identity);
+        }
+        if let Some(ca) = &self.ca_cert {
+            let bundle = std::fs::read_to_string(ca)?;
+            let end = "-----END CERTIFICATE-----";
+            for pem in bundle.split_inclusive(end).filter(|pem| pem.contains(end)) {
+                builder = builder.add_root_certificate(Certificate::from_pem(pem.as_bytes())?);
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    fn request(&self) -> DiscoveryRequest {
+        let mut request = DiscoveryRequest::new(
+            self.service_cluster.clone(),
+            self.envoy_version.clone(),
+            self.resource_names.clone(),
+        )
+        .with_metadata(self.metadata());
+        if let Some(id) = &self.node_id {
+            request = request.with_node_id(id.clone());
+        }
+        if self.region.is_some() || self.zone.is_some() || self.sub_zone.is_some() {
+            request = request.with_locality(Locality {
+                region: self.region.clone(),
+                zone: self.zone.clone(),
+                sub_zone: self.sub_zone.clone(),
+            });
+        }
+        if self.version_info.is_some() {
+            request.version_info = self.version_info.clone();
+        }
+        request
+    }
+}
+
+/// What the server answered with
+enum Answer {
+    NotModified,
+    Resources {
+        version_info: String,
+        resources: JsonValue,
+    },
+}
+
+async fn poll(client: &Client, url: &str, request: &DiscoveryRequest) -> anyhow::Result<Answer> {
+    let response = client.post(url).json(request).send().await?;
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(Answer::NotModified);
+    }
+    let body = response.text().await?;
+    if !status.is_success() {
+        anyhow::bail!("{status}: {body}");
+    }
+    let mut body: JsonValue = serde_json::from_str(&body)?;
+    Ok(Answer::Resources {
+        version_info: body["version_info"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        resources: body["resources"].take(),
+    })
+}
+
+fn display(resources: &JsonValue, output: Output) -> String {
+    match output {
+        Output::Json => serde_json::to_string_pretty(resources).unwrap() + "\n",
+        Output::Yaml => serde_yaml::to_string(resources).unwrap(),
+        Output::Names => resources
+            .as_array()
+            .map(|resources| {
+                resources
+                    .iter()
+                    .map(|r| {
+                        // Load assignments are named by their cluster
+                        let name = r.get("name").or_else(|| r.get("cluster_name"));
+                        name.and_then(|n| n.as_str())
+                            .unwrap_or("<unnamed>")
+                            .to_string()
+                            + "\n"
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
-    let resource_type = args.resource_type;
-    let req = DiscoveryRequest::new(
-        args.service_cluster,
-        args.envoy_version,
-        args.resource_names,
+    let url = format!(
+        "{}/{}/discovery:{}",
+        args.url.trim_end_matches('/'),
+        args.api_version,
+        args.resource_type
     );
-    let client = Client::new();
-    println!("Getting response");
-    let response = client
-        .post(format!(
-            "http://localhost:8070/v3/discovery:{resource_type}"
-        ))
-        .json(&req)
-        .send()
-        .await
-        .unwrap();
-    println!("{:?}", response);
-    println!("{}", response.text().await.unwrap());
+    let mut request = args.request();
+    let client = match args.client() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Could not set up the client: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut previous: Option<String> = None;
+
+    loop {
+        let sent = request.version_info.clone().unwrap_or_default();
+        match poll(&client, &url, &request).await {
+            Ok(Answer::NotModified) => eprintln!("version_info {sent}: not modified"),
+            Ok(Answer::Resources {
+                version_info,
+                resources,
+            }) => {
+                eprintln!("version_info {sent} -> {version_info}");
+                let current = display(&resources, args.output);
+                match &previous {
+                    Some(previous) => print!(
+                        "{}",
+                        TextDiff::from_lines(previous, &current)
+                            .unified_diff()
+                            .header(&sent, &version_info)
+                    ),
+                    None => print!("{current}"),
+                }
+                previous = Some(current);
+                request.version_info = Some(version_info);
+            }
+            Err(e) if args.watch => eprintln!("{e}"),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        if !args.watch {
+            return ExitCode::SUCCESS;
+        }
+        sleep(Duration::from_secs(args.interval)).await;
+    }
 }
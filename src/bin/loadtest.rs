@@ -0,0 +1,232 @@
+use clap::Parser;
+use reqwest::{Client, StatusCode};
+use serde_json::Value as JsonValue;
+use sovereign_rs::envoy_types::{DiscoveryRequest, Locality};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use xxhash_rust::xxh64::xxh64;
+
+/// Simulates a fleet of Envoy nodes polling a control plane, and reports how it held up.
+///
+/// `loadtest/sovereign.yaml` serves every resource type to the default fleet.
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, default_value = "http://localhost:8080", env = "SOVEREIGN_URL")]
+    url: String,
+
+    #[arg(long, default_value = "v3")]
+    api_version: String,
+
+    /// How many nodes to simulate
+    #[arg(long, default_value_t = 100)]
+    nodes: usize,
+
+    /// Nodes are spread across every combination of these clusters, versions and zones
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "cluster-a,cluster-b,cluster-c"
+    )]
+    clusters: Vec<String>,
+
+    #[arg(long, value_delimiter = ',', default_value = "1.24.0,1.25.0,1.26.0")]
+    envoy_versions: Vec<String>,
+
+    #[arg(long, value_delimiter = ',', default_value = "zone-a,zone-b")]
+    zones: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "clusters,listeners,routes"
+    )]
+    resource_types: Vec<String>,
+
+    /// Seconds between each node's polls, like Envoy's `refresh_delay`
+    #[arg(long, default_value_t = 5.0)]
+    interval: f64,
+
+    /// Fraction of the interval each poll is randomly moved by
+    #[arg(long, default_value_t = 0.1)]
+    jitter: f64,
+
+    /// Seconds to run for
+    #[arg(long, default_value_t = 60)]
+    duration: u64,
+}
+
+impl Args {
+    fn node(&self, i: usize) -> DiscoveryRequest {
+        // Each dimension is a digit of `i`, so consecutive nodes cover every combination
+        // rather than only those where the lists' lengths line up
+        let mut rest = i;
+        let mut pick = |values: &[String]| {
+            let value = values[rest % values.len()].clone();
+            rest /= values.len();
+            value
+        };
+        let cluster = pick(&self.clusters);
+        let version = pick(&self.envoy_versions);
+        let zone = pick(&self.zones);
+        DiscoveryRequest::new(cluster, version, None)
+            .with_node_id(format!("loadtest-{i}"))
+            .with_locality(Locality {
+                zone: Some(zone),
+                ..Locality::default()
+            })
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    modified: usize,
+    not_modified: usize,
+    errors: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    fn record(&mut self, latency: Duration, outcome: Result<StatusCode, String>) {
+        self.latencies.push(latency);
+        match outcome {
+            Ok(StatusCode::OK) => self.modified += 1,
+            Ok(StatusCode::NOT_MODIFIED) => self.not_modified += 1,
+            Ok(status) => *self.errors.entry(status.to_string()).or_default() += 1,
+            Err(e) => *self.errors.entry(e).or_default() += 1,
+        }
+    }
+
+    fn report(&mut self, elapsed: Duration) {
+        self.latencies.sort();
+        let total = self.latencies.len();
+        let percentile = |p: f64| {
+            let i = ((total as f64 * p).ceil() as usize).clamp(1, total.max(1)) - 1;
+            self.latencies.get(i).copied().unwrap_or_default()
+        };
+        let answered = self.modified + self.not_modified;
+        println!("requests:   {total}");
+        println!(
+            "throughput: {:.1} req/s",
+            total as f64 / elapsed.as_secs_f64()
+        );
+        for (name, p) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            println!("latency {name}: {:?}", percentile(p));
+        }
+        println!(
+            "304 ratio:  {:.1}% ({} of {answered} answered)",
+            100.0 * self.not_modified as f64 / answered.max(1) as f64,
+            self.not_modified
+        );
+        for (error, count) in self.errors.iter() {
+            println!("errors:     {count} x {error}");
+        }
+    }
+}
+
+/// A node polling every resource type, carrying version_info forward like Envoy does
+async fn simulate(
+    args: Arc<Args>,
+    i: usize,
+    client: Client,
+    stats: Arc<Mutex<Stats>>,
+    deadline: Instant,
+) {
+    let mut requests: Vec<(String, DiscoveryRequest)> = args
+        .resource_types
+        .iter()
+        .map(|resource_type| {
+            let url = format!(
+                "{}/{}/discovery:{resource_type}",
+                args.url.trim_end_matches('/'),
+                args.api_version
+            );
+            (url, args.node(i))
+        })
+        .collect();
+
+    // Nodes start spread over the first interval rather than all at once
+    let interval = Duration::from_secs_f64(args.interval);
+    sleep(interval.mul_f64(i as f64 / args.nodes as f64)).await;
+
+    let mut polls: u64 = 0;
+    while Instant::now() < deadline {
+        let started = Instant::now();
+        for (url, request) in requests.iter_mut() {
+            let start = Instant::now();
+            let outcome = match client.post(url.as_str()).json(request).send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if status == StatusCode::OK {
+                        if let Ok(body) = response.json::<JsonValue>().await {
+                            request.version_info = body["version_info"].as_str().map(String::from);
+                        }
+                    }
+                    Ok(status)
+                }
+                Err(e) => Err(e.without_url().to_string()),
+            };
+            stats.lock().unwrap().record(start.elapsed(), outcome);
+        }
+        // A pseudo-random offset per node and poll, so the fleet doesn't fall into lockstep
+        polls += 1;
+        let seed = xxh64(&[(i as u64).to_le_bytes(), polls.to_le_bytes()].concat(), 0);
+        let offset = (seed as f64 / u64::MAX as f64 * 2.0 - 1.0) * args.jitter;
+        sleep_until(started + interval.mul_f64((1.0 + offset).max(0.0))).await;
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Arc::new(Args::parse());
+    let client = Client::new();
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+
+    println!(
+        "Simulating {} nodes polling {} resource types every {}s for {}s",
+        args.nodes,
+        args.resource_types.len(),
+        args.interval,
+        args.duration
+    );
+    let tasks: Vec<_> = (0..args.nodes)
+        .map(|i| {
+            tokio::spawn(simulate(
+                args.clone(),
+                i,
+                client.clone(),
+                stats.clone(),
+                deadline,
+            ))
+        })
+        .collect();
+
+    sleep_until(deadline).await;
+    for task in tasks.iter() {
+        task.abort();
+    }
+    stats.lock().unwrap().report(start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn nodes_cover_every_combination() {
+        let args = Args::parse_from(["loadtest"]);
+        let combinations: HashSet<String> = (0..18)
+            .map(|i| {
+                let node = serde_json::to_value(args.node(i)).unwrap()["node"].take();
+                format!(
+                    "{} {} {}",
+                    node["cluster"], node["build_version"], node["locality"]["zone"]
+                )
+            })
+            .collect();
+        assert_eq!(combinations.len(), 18);
+    }
+}
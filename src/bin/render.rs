@@ -0,0 +1,348 @@
+use clap::Parser;
+use minijinja::{Environment, Value as JinjaValue};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use similar::TextDiff;
+use sovereign_rs::app::{resources, templates_by_resource_type, Selection, State, Timings};
+use sovereign_rs::config::Settings;
+use sovereign_rs::context::{poll_context, NodeContextCache};
+use sovereign_rs::envoy_types::{DiscoveryRequest, Locality};
+use sovereign_rs::sources::{poll_sources, poll_sources_into_buckets, Source, SourceItem};
+use sovereign_rs::templates::XdsTemplate;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Renders every resource type for a set of node fixtures, without running the server
+#[derive(Parser, Debug)]
+struct Args {
+    /// Comma-separated config files
+    #[arg(long, default_value = "sovereign.yaml", env = "SOVEREIGN_CONFIG_PATH")]
+    config: String,
+
+    /// YAML or JSON list of nodes to render for
+    #[arg(long)]
+    nodes: PathBuf,
+
+    /// YAML or JSON list of instances, used instead of the configured sources
+    #[arg(long)]
+    instances: Option<PathBuf>,
+
+    /// YAML or JSON map of template context, used instead of the configured context
+    #[arg(long)]
+    context: Option<PathBuf>,
+
+    /// Only render these resource types
+    #[arg(long, value_delimiter = ',')]
+    resource_types: Option<Vec<String>>,
+
+    /// Directory to write rendered resources to, as `<node>/<resource type>.json`
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Directory of expected resources to compare against, laid out like `--output`
+    #[arg(long)]
+    golden: Option<PathBuf>,
+
+    /// Also render every template for a default node, including those no fixture selects
+    #[arg(long)]
+    all_templates: bool,
+}
+
+#[derive(Deserialize)]
+struct NodeFixture {
+    name: String,
+    cluster: String,
+    #[serde(default = "default_envoy_version")]
+    envoy_version: String,
+    id: Option<String>,
+    locality: Option<Locality>,
+    #[serde(default)]
+    metadata: HashMap<String, JsonValue>,
+    #[serde(default = "default_host_header")]
+    host_header: String,
+}
+
+fn default_envoy_version() -> String {
+    "1.25.0".to_string()
+}
+
+fn default_host_header() -> String {
+    "localhost".to_string()
+}
+
+impl Default for NodeFixture {
+    /// The node templates are rendered for when checking them outside of any fixture
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            cluster: "default".to_string(),
+            envoy_version: default_envoy_version(),
+            id: None,
+            locality: None,
+            metadata: HashMap::new(),
+            host_header: default_host_header(),
+        }
+    }
+}
+
+impl NodeFixture {
+    fn request(&self) -> DiscoveryRequest {
+        let mut request =
+            DiscoveryRequest::new(self.cluster.clone(), self.envoy_version.clone(), None)
+                .with_metadata(self.metadata.clone());
+        if let Some(id) = &self.id {
+            request = request.with_node_id(id.clone());
+        }
+        if let Some(locality) = &self.locality {
+            request = request.with_locality(locality.clone());
+        }
+        request
+    }
+}
+
+/// Fixtures are YAML, which also covers JSON
+fn read_fixture<T: for<'de> Deserialize<'de>>(path: &Path) -> anyhow::Result<T> {
+    let content = std::fs::read_to_string(path)?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Could not parse {}: {e}", path.display()))
+}
+
+async fn state(args: &Args, settings: &Settings) -> anyhow::Result<State<'static>> {
+    let source_items = match &args.instances {
+        Some(path) => Some(vec![SourceItem {
+            source: Source::Inline {
+                data: read_fixture(path)?,
+            },
+            transforms: vec![],
+        }]),
+        None => settings.sources.as_ref().map(|s| s.items.clone()),
+    };
+    let instances = match source_items {
+        Some(items) => {
+            let index = match &settings.node_matching {
+                Some(matching) => {
+                    let serve_unmatched =
+                        settings.sources.as_ref().is_some_and(|s| s.serve_unmatched);
+                    poll_sources_into_buckets(&items, matching, serve_unmatched)?
+                }
+                None => poll_sources(&items)?,
+            };
+            // The receiver keeps the last value after the sender is dropped
+            Some(watch::channel(Arc::new(index)).1)
+        }
+        None => None,
+    };
+
+    let context = match &args.context {
+        Some(path) => Some(JinjaValue::from_serializable(&read_fixture::<JsonValue>(
+            path,
+        )?)),
+        None => match &settings.template_context {
+            Some(c) => Some(poll_context(&c.items).await?),
+            None => None,
+        },
+    };
+
+    // Fixture context replaces the per-node items too
+    let node_context = match (&args.context, &settings.template_context) {
+        (None, Some(c)) if !c.per_node.is_empty() => {
+            Some(NodeContextCache::new(c.per_node.clone()))
+        }
+        _ => None,
+    };
+
+    Ok(State {
+        instances,
+        context: context.map(|c| watch::channel(c).1),
+        context_status: None,
+        source_status: None,
+        max_staleness: None,
+        node_context,
+        templates: templates_by_resource_type(&settings.all_templates()?),
+        env: Environment::new(),
+        auth: None,
+        secrets: None,
+        git: None,
+        draining: AtomicBool::new(false),
+    })
+}
+
+/// Node names become directories under `--output` and `--golden`, so must be plain file names
+fn check_name(node: &NodeFixture) -> anyhow::Result<()> {
+    let mut components = Path::new(&node.name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => anyhow::bail!("Node name {:?} is not a plain file name", node.name),
+    }
+}
+
+/// Renders one resource type for a node, returning pretty-printed resources
+async fn render(
+    state: &State<'_>,
+    node: &NodeFixture,
+    resource_type: &str,
+) -> Result<String, String> {
+    let request = node.request();
+    let resource = format!("discovery:{resource_type}");
+    let selection = state
+        .select(&resource, &request, &node.host_header)
+        .map_err(|e| e.to_string())?;
+    render_selection(state, node, &selection).await
+}
+
+/// Renders a template chosen up front, so that those no fixture selects are still checked
+async fn render_template(state: &State<'_>, template: &XdsTemplate) -> Result<String, String> {
+    let node = NodeFixture::default();
+    let selection = Selection {
+        resource_type: template.resource_type.clone(),
+        version: node.request().envoy_version().map_err(|e| e.to_string())?,
+        template: template.clone(),
+    };
+    render_selection(state, &node, &selection).await
+}
+
+async fn render_selection(
+    state: &State<'_>,
+    node: &NodeFixture,
+    selection: &Selection,
+) -> Result<String, String> {
+    let request = node.request();
+    let context = state
+        .context(&request, &node.host_header, None, &mut Timings::new())
+        .await;
+    let text = state
+        .render(selection, context)
+        .map_err(|e| e.to_string())?;
+    let res = resources(selection, &text).map_err(|e| match e.body().get("excerpt") {
+        Some(JsonValue::String(excerpt)) => format!("{e}\n{excerpt}"),
+        _ => e.to_string(),
+    })?;
+    let parsed: JsonValue = serde_json::from_str(&res).map_err(|e| {
+        format!(
+            "Template {} did not render JSON: {e}",
+            selection.template.name()
+        )
+    })?;
+    Ok(serde_json::to_string_pretty(&parsed).unwrap() + "\n")
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    pyo3::prepare_freethreaded_python();
+
+    let settings = match Settings::from_paths(&args.config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not load config: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // A checkout of its own, leaving alone any server that shares the checkout dir
+    let checkout = settings.git.as_ref().map(|git| git.checkout_private());
+    if let Some(Err(e)) = &checkout {
+        eprintln!("Could not check out git repository: {e}");
+        return ExitCode::FAILURE;
+    }
+    let loaded = match read_fixture::<Vec<NodeFixture>>(&args.nodes) {
+        Ok(nodes) => match nodes.iter().try_for_each(check_name) {
+            Ok(()) => state(&args, &settings).await.map(|state| (nodes, state)),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    let (nodes, state) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let resource_types: BTreeSet<String> = match &args.resource_types {
+        Some(types) => types.iter().cloned().collect(),
+        None => state.templates.iter().map(|t| t.key().clone()).collect(),
+    };
+
+    let mut failures = 0;
+    for node in nodes.iter() {
+        for resource_type in resource_types.iter() {
+            let label = format!("{}/{resource_type}", node.name);
+            let rendered = match render(&state, node, resource_type).await {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    eprintln!("FAIL {label}: {e}");
+                    failures += 1;
+                    continue;
+                }
+            };
+            let file = PathBuf::from(&node.name).join(format!("{resource_type}.json"));
+
+            if let Some(output) = &args.output {
+                let path = output.join(&file);
+                let written = std::fs::create_dir_all(path.parent().unwrap())
+                    .and_then(|_| std::fs::write(&path, &rendered));
+                if let Err(e) = written {
+                    eprintln!("FAIL {label}: could not write {}: {e}", path.display());
+                    failures += 1;
+                    continue;
+                }
+            }
+
+            if let Some(golden) = &args.golden {
+                let path = golden.join(&file);
+                let expected = match std::fs::read_to_string(&path) {
+                    Ok(expected) => expected,
+                    Err(e) => {
+                        eprintln!("FAIL {label}: could not read {}: {e}", path.display());
+                        failures += 1;
+                        continue;
+                    }
+                };
+                if expected != rendered {
+                    let diff = TextDiff::from_lines(&expected, &rendered);
+                    eprintln!("FAIL {label}: differs from {}", path.display());
+                    eprint!(
+                        "{}",
+                        diff.unified_diff()
+                            .header(&path.to_string_lossy(), "rendered")
+                    );
+                    failures += 1;
+                    continue;
+                }
+            }
+            println!("ok   {label}");
+        }
+    }
+
+    // Templates that no fixture selects would otherwise go unchecked until a node requests them.
+    // The default node may be nothing like a real one, so this is opt-in.
+    let mut templates: Vec<XdsTemplate> = state
+        .templates
+        .iter()
+        .filter(|t| args.all_templates && resource_types.contains(t.key()))
+        .flat_map(|t| t.value().clone())
+        .collect();
+    templates.sort_by(|a, b| (a.name(), a.path()).cmp(&(b.name(), b.path())));
+    for template in templates {
+        let label = format!("template {}", template.name());
+        match render_template(&state, &template).await {
+            Ok(_) => println!("ok   {label}"),
+            Err(e) => {
+                eprintln!("FAIL {label}: {e}");
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        eprintln!("{failures} render(s) failed");
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
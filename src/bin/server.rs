@@ -2,19 +2,34 @@ use axum::extract::Extension;
 use axum::routing::{get, post};
 use axum::Router;
 use clap::Parser;
-use dashmap::DashMap;
-use minijinja::{Environment, Value as JinjaValue};
-use sovereign_rs::app::{discovery, healthcheck, State};
-use sovereign_rs::config::{Settings, SourceConfig, TemplateContextConfig};
-use sovereign_rs::context::poll_context;
-use sovereign_rs::sources::{poll_sources, poll_sources_into_buckets, InstancesPackage};
+use minijinja::Environment;
+use sovereign_rs::app::{
+    context_status, debug_render, detailed_readiness, discovery, git_status, healthcheck, liveness,
+    readiness, templates_by_resource_type, State,
+};
+use sovereign_rs::config::{config_path, SecretsConfig, Settings, SourceConfig};
+use sovereign_rs::context::{watch_context, NodeContextCache, NODE_CONTEXT_SWEEP_INTERVAL};
+use sovereign_rs::health::HealthMap;
+use sovereign_rs::listeners::{
+    bind_unix, serve_unix, terminated, ListenAddress, ListenerConfig, Routes,
+};
+use sovereign_rs::secrets::{poll_secrets, SecretStore};
+use sovereign_rs::sources::{poll_sources_with_health, InstanceIndex};
+#[cfg(feature = "tls")]
+use sovereign_rs::tls::TlsConfig;
+use sovereign_rs::validate::validate;
 use std::net::IpAddr;
 use std::net::SocketAddr;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::signal::ctrl_c;
 use tokio::sync::watch::{self, Receiver};
-use tokio::time::{sleep, Duration};
-use tracing::debug;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+#[cfg(feature = "tls")]
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[derive(Parser, Debug)]
@@ -24,50 +39,102 @@ struct Args {
 
     #[clap(long, default_value_t = 8080, env = "SOVEREIGN_PORT")]
     pub listen_port: u16,
+
+    /// Check the config for problems and exit, without serving
+    #[clap(long)]
+    pub validate: bool,
+
+    /// With --validate, also load every context item as the server would at startup
+    #[clap(long, requires = "validate")]
+    pub load_context: bool,
+
+    /// Serve TLS with this PEM certificate chain
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "SOVEREIGN_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "SOVEREIGN_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require client certificates signed by a CA in this PEM bundle
+    #[cfg(feature = "tls")]
+    #[clap(long, env = "SOVEREIGN_TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Seconds between checks for changed certificate files
+    #[cfg(feature = "tls")]
+    #[clap(long, default_value_t = 30, env = "SOVEREIGN_TLS_RELOAD_INTERVAL")]
+    pub tls_reload_interval: u64,
 }
 
-fn setup_context_channel(config: TemplateContextConfig) -> Receiver<JinjaValue> {
-    let (tx, rx) = watch::channel(poll_context(&config.items));
-    tokio::spawn(async move {
-        loop {
-            sleep(config.interval).await;
-            let ctx = poll_context(&config.items);
-            _ = tx.send(ctx);
-        }
-    });
-    rx
+#[cfg(feature = "tls")]
+impl Args {
+    fn tls(&self) -> Option<TlsConfig> {
+        Some(TlsConfig {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+            reload_interval: Duration::from_secs(self.tls_reload_interval),
+        })
+    }
 }
 
 fn setup_sources_channel(
     settings: Settings,
     config: SourceConfig,
-) -> Receiver<Vec<InstancesPackage>> {
-    if let Some(matching) = settings.node_matching {
-        let instances = poll_sources_into_buckets(&config.items, &matching.source_key).unwrap();
-        let (tx, rx) = watch::channel(instances);
-        tokio::spawn(async move {
-            loop {
-                sleep(config.interval).await;
-                if let Ok(sources) = poll_sources_into_buckets(&config.items, &matching.source_key)
-                {
-                    _ = tx.send(sources);
-                }
+    tasks: &mut JoinSet<()>,
+) -> (Receiver<Arc<InstanceIndex>>, Arc<HealthMap>) {
+    let health = Arc::new(HealthMap::default());
+    let matching = settings.node_matching;
+    // No instances are served until a poll succeeds, and readiness fails meanwhile
+    let initial = match poll_sources_with_health(
+        &config.items,
+        matching.as_ref(),
+        config.serve_unmatched,
+        &health,
+    ) {
+        Ok(index) => index,
+        Err(e) => {
+            warn!("Could not poll sources: {e}");
+            InstanceIndex::default()
+        }
+    };
+    let (tx, rx) = watch::channel(Arc::new(initial));
+    let recorded = health.clone();
+    tasks.spawn(async move {
+        loop {
+            sleep(config.interval).await;
+            match poll_sources_with_health(
+                &config.items,
+                matching.as_ref(),
+                config.serve_unmatched,
+                &recorded,
+            ) {
+                Ok(index) => _ = tx.send(Arc::new(index)),
+                Err(e) => warn!("Could not poll sources: {e}"),
             }
-        });
-        rx
-    } else {
-        let initial = poll_sources(&config.items).unwrap();
-        let (tx, rx) = watch::channel(initial);
-        tokio::spawn(async move {
-            loop {
-                sleep(Duration::from_secs(30)).await;
-                if let Ok(sources) = poll_sources(&config.items) {
-                    _ = tx.send(sources);
-                }
+        }
+    });
+    (rx, health)
+}
+
+fn setup_secrets_channel(
+    config: SecretsConfig,
+    tasks: &mut JoinSet<()>,
+) -> anyhow::Result<Receiver<Arc<SecretStore>>> {
+    let initial = poll_secrets(&config.items)?;
+    let (tx, rx) = watch::channel(Arc::new(initial));
+    tasks.spawn(async move {
+        loop {
+            sleep(config.interval).await;
+            match poll_secrets(&config.items) {
+                Ok(secrets) => _ = tx.send(Arc::new(secrets)),
+                Err(e) => warn!("{e}"),
             }
-        });
-        rx
-    }
+        }
+    });
+    Ok(rx)
 }
 
 #[tokio::main]
@@ -75,6 +142,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     pyo3::prepare_freethreaded_python();
 
+    if args.validate {
+        let problems = validate(&config_path(), args.load_context).await;
+        for problem in problems.iter() {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            eprintln!("{} problem(s) found", problems.len());
+            std::process::exit(1);
+        }
+        println!("Config is valid");
+        return Ok(());
+    }
+
     FmtSubscriber::builder()
         .with_env_filter(EnvFilter::from_default_env())
         .event_format(tracing_subscriber::fmt::format())
@@ -89,48 +169,242 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    // Polling tasks are cancelled once the listeners have stopped
+    let mut tasks = JoinSet::new();
+
+    // Everything else may read files from the checkout, so it comes first
+    debug!(target: "sovereign_rs", "Setting up git checkout");
+    let git_rx = match settings.git.clone() {
+        Some(git) => Some(git.watch(&mut tasks)?),
+        None => None,
+    };
+    debug!(target: "sovereign_rs", "Completed setting up git checkout");
+
     debug!(target: "sovereign_rs", "Setting up sources channel");
     let mut sources_rx = None;
+    let mut source_status = None;
     if let Some(source_conf) = &settings.sources {
-        sources_rx = Some(setup_sources_channel(settings.clone(), source_conf.clone()));
+        let (rx, health) = setup_sources_channel(settings.clone(), source_conf.clone(), &mut tasks);
+        sources_rx = Some(rx);
+        source_status = Some(health);
     }
     debug!(target: "sovereign_rs", "Completed setting up sources channel");
 
     debug!(target: "sovereign_rs", "Setting up context channel");
     let mut context_rx = None;
+    let mut context_store = None;
     if let Some(context_conf) = &settings.template_context {
-        context_rx = Some(setup_context_channel(context_conf.clone()));
+        let (rx, store) = watch_context(context_conf.clone(), &mut tasks).await;
+        context_rx = Some(rx);
+        context_store = Some(store);
     }
+    let node_context = settings
+        .template_context
+        .as_ref()
+        .filter(|c| !c.per_node.is_empty())
+        .map(|c| NodeContextCache::new(c.per_node.clone()));
     debug!(target: "sovereign_rs", "Completed setting up context channel");
 
+    debug!(target: "sovereign_rs", "Setting up secrets channel");
+    let secrets_rx = settings
+        .secrets
+        .clone()
+        .map(|config| setup_secrets_channel(config, &mut tasks))
+        .transpose()?;
+    debug!(target: "sovereign_rs", "Completed setting up secrets channel");
+
     debug!(target: "sovereign_rs", "Setting up templates");
-    let templates = DashMap::new();
-    for template in settings.templates.iter() {
-        templates.insert(template.name(), template.clone());
-    }
+    let templates = templates_by_resource_type(&settings.all_templates()?);
     debug!(target: "sovereign_rs", "Completed setting up templates");
 
     let state = Arc::new(State {
         instances: sources_rx,
         context: context_rx,
+        context_status: context_store,
+        source_status,
+        max_staleness: settings.readiness.max_staleness,
+        node_context,
         env: Environment::new(),
+        auth: settings.auth.clone(),
+        secrets: secrets_rx,
+        git: git_rx,
+        draining: AtomicBool::new(false),
         templates,
     });
 
-    let app = Router::new()
-        .route("/healthcheck", get(healthcheck))
-        .route("/:version/*resource", post(discovery))
-        .layer(Extension(state));
+    if state.node_context.is_some() {
+        let sweeping = state.clone();
+        tasks.spawn(async move {
+            loop {
+                sleep(NODE_CONTEXT_SWEEP_INTERVAL).await;
+                if let Some(cache) = &sweeping.node_context {
+                    cache.sweep();
+                }
+            }
+        });
+    }
+
+    let mut listeners = settings.listeners.clone();
+    if listeners.is_empty() {
+        let addr = SocketAddr::new(args.listen_address, args.listen_port);
+        listeners.push(ListenerConfig::all(addr));
+    }
+
+    // Readiness fails for the drain period while requests are still served,
+    // then the listeners stop accepting and finish the requests in flight within the grace period
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let drain_period = settings.shutdown.drain_period;
+    let grace_period = settings.shutdown.grace_period;
+    let draining = state.clone();
+    tokio::spawn(async move {
+        terminated().await;
+        info!(?drain_period, "Draining before shutting down");
+        draining.draining.store(true, Ordering::Relaxed);
+        sleep(drain_period).await;
+        debug!(target: "sovereign_rs", "Shutting down gracefully");
+        _ = shutdown_tx.send(());
+    });
+    let shutdown = || {
+        let mut rx = shutdown_rx.clone();
+        async move {
+            _ = rx.changed().await;
+        }
+    };
+
+    #[cfg(feature = "tls")]
+    let acceptor = args.tls().map(|tls| tls.watch()).transpose()?;
 
     debug!(target: "sovereign_rs", "Starting server");
-    let addr = SocketAddr::new(args.listen_address, args.listen_port);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(async {
-            ctrl_c().await.unwrap();
-            debug!(target: "sovereign_rs", "Shutting down gracefully")
-        })
-        .await?;
+    let mut servers = JoinSet::new();
+    for listener in listeners.iter() {
+        let app = router(listener, state.clone());
+        info!(address = %listener.address, routes = ?listener.routes, "Listening");
+        match &listener.address {
+            ListenAddress::Unix(path) => {
+                servers.spawn(serve_unix(bind_unix(path)?, app, shutdown(), grace_period));
+            }
+            ListenAddress::Tcp(addr) => {
+                #[cfg(feature = "tls")]
+                if let (true, Some(acceptor)) = (listener.tls, &acceptor) {
+                    let tcp = tokio::net::TcpListener::bind(addr).await?;
+                    servers.spawn(sovereign_rs::tls::serve(
+                        tcp,
+                        acceptor.clone(),
+                        app,
+                        shutdown(),
+                        grace_period,
+                    ));
+                    continue;
+                }
+                let server = axum::Server::try_bind(addr)?
+                    .serve(app.into_make_service())
+                    .with_graceful_shutdown(shutdown());
+                let closed = shutdown();
+                servers.spawn(async move {
+                    tokio::select! {
+                        result = server => Ok(result?),
+                        _ = async { closed.await; sleep(grace_period).await } => {
+                            warn!("Closing connections still open after the grace period");
+                            Ok(())
+                        }
+                    }
+                });
+            }
+        }
+    }
+    // One listener failing stops the server, rather than leaving it half up
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+    tasks.shutdown().await;
 
     Ok(())
 }
+
+/// The routes a listener serves, which all share the same state
+fn router(listener: &ListenerConfig, state: Arc<State<'static>>) -> Router {
+    let mut app = Router::new()
+        .route("/healthcheck", get(healthcheck))
+        .route("/health/live", get(liveness));
+    if listener.serves(Routes::Admin) {
+        app = app
+            .route("/health/ready", get(detailed_readiness))
+            .route("/status/context", get(context_status))
+            .route("/status/git", get(git_status))
+            .route("/debug/render/:version/*resource", post(debug_render));
+    }
+    if listener.serves(Routes::Discovery) {
+        app = app.route("/:version/*resource", post(discovery));
+    }
+    if !listener.serves(Routes::Admin) {
+        app = app.route("/health/ready", get(readiness));
+    }
+    app.layer(Extension(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::Value as JsonValue;
+    use sovereign_rs::envoy_types::DiscoveryRequest;
+    use sovereign_rs::templates::discover;
+    use tower::ServiceExt;
+
+    async fn post(app: &Router, uri: &str) -> (StatusCode, JsonValue) {
+        let request = DiscoveryRequest::new("edge".into(), "1.25.0".into(), None);
+        let request = Request::post(uri)
+            .header("host", "localhost")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&request).unwrap()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn serving(routes: Vec<Routes>) -> (Router, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("default")).unwrap();
+        std::fs::write(
+            dir.path().join("default/clusters.yaml.jinja2"),
+            "- name: {{ discovery_request.node.cluster }}\n",
+        )
+        .unwrap();
+        let state = State {
+            templates: templates_by_resource_type(&discover(dir.path()).unwrap()),
+            ..State::default()
+        };
+        let listener = ListenerConfig {
+            routes,
+            ..ListenerConfig::all("127.0.0.1:0".parse().unwrap())
+        };
+        (router(&listener, Arc::new(state)), dir)
+    }
+
+    #[tokio::test]
+    async fn debug_render_is_routed_beside_discovery() {
+        let (app, _dir) = serving(vec![Routes::Discovery, Routes::Admin]);
+        let (status, body) = post(&app, "/v3/discovery:clusters").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["resources"][0]["name"], "edge");
+        let (status, body) = post(&app, "/debug/render/v3/discovery:clusters").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["rendered"], "- name: edge");
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_only_served_where_configured() {
+        let (app, _dir) = serving(vec![Routes::Discovery]);
+        let (_, body) = post(&app, "/debug/render/v3/discovery:clusters").await;
+        assert!(body.get("rendered").is_none());
+        let (status, _) = post(&app, "/v3/discovery:clusters").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (app, _dir) = serving(vec![Routes::Admin]);
+        let (status, _) = post(&app, "/v3/discovery:clusters").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,20 +1,30 @@
-use crate::context::TemplateContext;
-use crate::sources::Source;
-use crate::templates::XdsTemplate;
-use config::{Config, ConfigError, Environment, File};
+use crate::auth::AuthConfig;
+use crate::context::{NodeContext, TemplateContext};
+use crate::git::GitConfig;
+use crate::health::ReadinessConfig;
+use crate::listeners::{ListenerConfig, ShutdownConfig};
+use crate::matching::NodeMatching;
+use crate::secrets::SecretItem;
+use crate::sources::SourceItem;
+use crate::templates::{discover, XdsTemplate};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use config::{Config, ConfigError, Environment, File, Map, Source, Value, ValueKind};
+use regex::Regex;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
 use tokio::time::Duration;
 
-#[derive(Deserialize, Clone)]
-pub struct NodeMatching {
-    pub source_key: String,
-}
-
 #[derive(Deserialize, Clone)]
 pub struct TemplateContextConfig {
+    #[serde(default)]
     pub items: HashMap<String, TemplateContext>,
+    /// Context fetched for each node when it makes a request
+    #[serde(default)]
+    pub per_node: HashMap<String, NodeContext>,
     #[serde(
         deserialize_with = "deserialize_duration",
         default = "default_duration"
@@ -24,7 +34,21 @@ pub struct TemplateContextConfig {
 
 #[derive(Deserialize, Clone)]
 pub struct SourceConfig {
-    pub items: Vec<Source>,
+    pub items: Vec<SourceItem>,
+    /// Serve instances holding none of the `node_matching` keys to every node, instead of
+    /// dropping them
+    #[serde(default)]
+    pub serve_unmatched: bool,
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        default = "default_duration"
+    )]
+    pub interval: Duration,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SecretsConfig {
+    pub items: Vec<SecretItem>,
     #[serde(
         deserialize_with = "deserialize_duration",
         default = "default_duration"
@@ -34,13 +58,28 @@ pub struct SourceConfig {
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
+    /// Joined from a config file and the files it includes, rather than replaced by them
+    #[serde(default)]
     pub templates: Vec<XdsTemplate>,
+    /// Directories of templates laid out as `<envoy version>/<resource type>.<ext>`
+    #[serde(default)]
+    pub template_dirs: Vec<PathBuf>,
     pub sources: Option<SourceConfig>,
     pub template_context: Option<TemplateContextConfig>,
     pub node_matching: Option<NodeMatching>,
+    pub auth: Option<AuthConfig>,
+    pub secrets: Option<SecretsConfig>,
+    pub git: Option<GitConfig>,
+    /// Replaces the listener given by `--listen-address` and `--listen-port`
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
 }
 
-fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -48,20 +87,418 @@ where
     Ok(Duration::from_secs(secs))
 }
 
+pub(crate) fn deserialize_optional_duration<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = Option::<u64>::deserialize(deserializer)?;
+    Ok(secs.map(Duration::from_secs))
+}
+
 fn default_duration() -> Duration {
     Duration::from_secs(30)
 }
 
+/// The config files named by `SOVEREIGN_CONFIG_PATH`
+pub fn config_path() -> String {
+    env::var("SOVEREIGN_CONFIG_PATH").unwrap_or_else(|_| "sovereign.yaml".into())
+}
+
+/// The files a config file includes, relative to its own directory
+fn includes(path: &str) -> Result<Vec<String>, ConfigError> {
+    let file = Config::builder()
+        .add_source(File::with_name(path))
+        .build()?;
+    let includes = match file.get::<Vec<String>>("include") {
+        Ok(includes) => includes,
+        Err(ConfigError::NotFound(_)) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    Ok(includes
+        .iter()
+        .map(|include| normalize(&dir.join(include)).to_string_lossy().to_string())
+        .collect())
+}
+
+/// Removes `..` lexically, so that a file reached by different routes has one name
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The file and everything it includes, included files first so that the file overrides them
+fn expand(path: &str, chain: &mut Vec<String>, files: &mut Vec<String>) -> Result<(), ConfigError> {
+    if chain.iter().any(|p| p == path) {
+        return Err(ConfigError::Message(format!(
+            "{path} includes itself via {}",
+            chain.join(" -> ")
+        )));
+    }
+    chain.push(path.to_string());
+    for include in includes(path)? {
+        expand(&include, chain, files)?;
+    }
+    chain.pop();
+    files.push(path.to_string());
+    Ok(())
+}
+
+/// Each file in `SOVEREIGN_CONFIG_PATH` with the files it includes, in the order they are merged
+fn config_groups(config_path: &str) -> Result<Vec<Vec<String>>, ConfigError> {
+    // A file included twice is merged where it first appears
+    let mut added = HashSet::new();
+    let mut groups = vec![];
+    for path in config_path.split(',') {
+        let mut files = vec![];
+        expand(path, &mut vec![], &mut files)?;
+        files.retain(|path| added.insert(path.clone()));
+        groups.push(files);
+    }
+    Ok(groups)
+}
+
+/// Every config file and the files it includes, in the order they are merged
+pub fn config_files(config_path: &str) -> Result<Vec<String>, ConfigError> {
+    Ok(config_groups(config_path)?.concat())
+}
+
+/// Lists that are joined across a file and the files it includes, where other lists are replaced
+const CONCATENATED: [&str; 2] = ["templates", "template_dirs"];
+
+/// Merges config files, the files they include, and env var overrides, before deserializing them.
+/// Later files override earlier ones. The lists in [`CONCATENATED`] hold the entries of a file
+/// and everything it includes, and are only replaced by a later file in `SOVEREIGN_CONFIG_PATH`.
+pub fn load(config_path: &str) -> Result<Config, ConfigError> {
+    let mut s = Config::builder();
+    let mut lists: Map<String, Vec<Value>> = Map::new();
+    for group in config_groups(config_path)? {
+        let mut joined: Map<String, Vec<Value>> = Map::new();
+        for path in group {
+            let file = File::with_name(&path);
+            let mut values = file.collect()?;
+            for key in CONCATENATED {
+                if let Some(list) = values.remove(key) {
+                    joined
+                        .entry(key.to_string())
+                        .or_default()
+                        .extend(list.into_array()?);
+                }
+            }
+            s = s.add_source(file);
+        }
+        lists.extend(joined);
+    }
+    s = s.add_source(Environment::with_prefix("SOVEREIGN"));
+    let mut values = s.build()?.collect()?;
+    for (key, list) in lists {
+        values.insert(key, Value::new(None, ValueKind::Array(list)));
+    }
+    for (key, value) in values.iter_mut() {
+        interpolate(key, value)?;
+    }
+    Config::builder().add_source(Interpolated(values)).build()
+}
+
+/// Config values after interpolation, as a source to build the final config from
+#[derive(Debug, Clone)]
+struct Interpolated(Map<String, Value>);
+
+impl Source for Interpolated {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Replaces `${env:NAME}`, `${file:/path}` and `${base64:...}` in every string under `key`.
+/// They are read once, when the config is loaded. `$${` is a literal `${`.
+fn interpolate(key: &str, value: &mut Value) -> Result<(), ConfigError> {
+    match &mut value.kind {
+        ValueKind::String(s) => *s = interpolate_str(key, s)?,
+        ValueKind::Table(table) => {
+            for (k, v) in table.iter_mut() {
+                interpolate(&format!("{key}.{k}"), v)?;
+            }
+        }
+        ValueKind::Array(array) => {
+            for (i, v) in array.iter_mut().enumerate() {
+                interpolate(&format!("{key}[{i}]"), v)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(key: &str, s: &str) -> Result<String, ConfigError> {
+    interpolate_with(key, s, &|name| env::var(name).ok())
+}
+
+/// Interpolates references, reading `${env:...}` through `env`
+fn interpolate_with(
+    key: &str,
+    s: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern =
+        PATTERN.get_or_init(|| Regex::new(r"\$\$\{|\$\{(env|file|base64):([^}]*)\}").unwrap());
+    let mut result = String::new();
+    let mut last = 0;
+    for captures in pattern.captures_iter(s) {
+        let whole = captures.get(0).unwrap();
+        let (Some(kind), Some(arg)) = (captures.get(1), captures.get(2)) else {
+            result.push_str(&s[last..whole.start()]);
+            result.push_str("${");
+            last = whole.end();
+            continue;
+        };
+        let arg = arg.as_str();
+        // Errors name the key and the reference, never the value
+        let value = match kind.as_str() {
+            "env" => env(arg).ok_or_else(|| format!("env var {arg} is not set")),
+            // Mounted secrets usually end in a newline that isn't part of the value
+            "file" => std::fs::read_to_string(arg)
+                .map(|content| content.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| format!("could not read {arg}: {e}")),
+            _ => STANDARD
+                .decode(arg)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| "invalid base64".to_string()),
+        }
+        .map_err(|e| ConfigError::Message(format!("{key}: {e}")))?;
+        result.push_str(&s[last..whole.start()]);
+        result.push_str(&value);
+        last = whole.end();
+    }
+    result.push_str(&s[last..]);
+    Ok(result)
+}
+
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
-        let config_path =
-            env::var("SOVEREIGN_CONFIG_PATH").unwrap_or_else(|_| "sovereign.yaml".into());
+        Self::from_paths(&config_path())
+    }
+
+    /// Loads settings from comma-separated config files, with env var overrides
+    pub fn from_paths(config_path: &str) -> Result<Self, ConfigError> {
+        let settings: Self = load(config_path)?.try_deserialize()?;
+        settings.check().map_err(ConfigError::Message)?;
+        Ok(settings)
+    }
+
+    /// Rejects settings that deserialize, but would be unsafe to serve
+    pub fn check(&self) -> Result<(), String> {
+        // Nodes name their own cluster, so only a credential can limit who gets which secret
+        if self.secrets.is_some() && self.auth.is_none() {
+            return Err("secrets are only served to authenticated clients, so need auth".into());
+        }
+        if let (Some(auth), Some(matching)) = (&self.auth, &self.node_matching) {
+            auth.check(matching)?;
+        }
+        if let (Some(sources), Some(matching)) = (&self.sources, &self.node_matching) {
+            for (i, item) in sources.items.iter().enumerate() {
+                if let Some(instance) = item.source.service_instance() {
+                    instance
+                        .check(matching)
+                        .map_err(|e| format!("sources.items[{i}]: {e}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let mut s = Config::builder();
-        for path in config_path.split(',') {
-            s = s.add_source(File::with_name(path));
+    /// The listed templates, followed by those found in `template_dirs`
+    pub fn all_templates(&self) -> anyhow::Result<Vec<XdsTemplate>> {
+        let mut templates = self.templates.clone();
+        for dir in self.template_dirs.iter() {
+            templates.extend(discover(dir).map_err(|e| {
+                anyhow::anyhow!("Could not read templates from {}: {e}", dir.display())
+            })?);
         }
-        s = s.add_source(Environment::with_prefix("SOVEREIGN"));
-        s.build()?.try_deserialize()
+        Ok(templates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value as JsonValue};
+    use std::fs::write;
+
+    fn template(resource_type: &str) -> String {
+        format!(
+            "  - path: {resource_type}.yaml\n    envoy_version: default\n    resource_type: {resource_type}\n"
+        )
+    }
+
+    #[test]
+    fn includes_are_merged_before_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("shared")).unwrap();
+        write(
+            dir.path().join("shared/base.yaml"),
+            format!(
+                "template_context:\n  interval: 10\ntemplates:\n{}",
+                template("clusters")
+            ),
+        )
+        .unwrap();
+        write(
+            dir.path().join("main.yaml"),
+            format!(
+                "include: [shared/base.yaml]\ntemplate_context:\n  interval: 20\ntemplates:\n{}",
+                template("listeners")
+            ),
+        )
+        .unwrap();
+        let settings: Settings = load(&dir.path().join("main.yaml").to_string_lossy())
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(
+            settings.template_context.unwrap().interval,
+            Duration::from_secs(20)
+        );
+        let types: Vec<&str> = settings
+            .templates
+            .iter()
+            .map(|t| t.resource_type.as_str())
+            .collect();
+        assert_eq!(types, ["clusters", "listeners"]);
+    }
+
+    #[test]
+    fn discovered_services_need_clusters_to_match_on() {
+        let settings = |config: JsonValue| -> Settings {
+            serde_json::from_value(json!({
+                "node_matching": {"source_key": "service_clusters"},
+                "sources": {"items": [{"type": "consul", "config": config}]},
+            }))
+            .unwrap()
+        };
+        let err = settings(json!({"service": "web"})).check().unwrap_err();
+        assert_eq!(
+            err,
+            "sources.items[0]: service_clusters is needed to match instances to nodes"
+        );
+        settings(json!({"service": "web", "service_clusters": ["edge"]}))
+            .check()
+            .unwrap();
+    }
+
+    #[test]
+    fn later_config_files_replace_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path().join("base.yaml"),
+            format!("templates:\n{}", template("clusters")),
+        )
+        .unwrap();
+        write(
+            dir.path().join("main.yaml"),
+            format!(
+                "include: [base.yaml]\ntemplates:\n{}",
+                template("listeners")
+            ),
+        )
+        .unwrap();
+        write(
+            dir.path().join("override.yaml"),
+            format!("templates:\n{}", template("routes")),
+        )
+        .unwrap();
+        write(dir.path().join("empty.yaml"), "template_context: {}\n").unwrap();
+        let types = |paths: &[&str]| -> Vec<String> {
+            let paths: Vec<String> = paths
+                .iter()
+                .map(|p| dir.path().join(p).to_string_lossy().to_string())
+                .collect();
+            let settings: Settings = load(&paths.join(",")).unwrap().try_deserialize().unwrap();
+            settings
+                .templates
+                .into_iter()
+                .map(|t| t.resource_type)
+                .collect()
+        };
+        assert_eq!(types(&["main.yaml", "override.yaml"]), ["routes"]);
+        // A file that doesn't set the list leaves it as it was
+        assert_eq!(
+            types(&["main.yaml", "empty.yaml"]),
+            ["clusters", "listeners"]
+        );
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("a.yaml"), "include: [b.yaml]\n").unwrap();
+        write(dir.path().join("b.yaml"), "include: [./a.yaml]\n").unwrap();
+        let err = load(&dir.path().join("a.yaml").to_string_lossy()).unwrap_err();
+        assert!(err.to_string().contains("includes itself"), "{err}");
+    }
+
+    /// Stands in for the process environment, which tests running in parallel can't safely change
+    fn env(name: &str) -> Option<String> {
+        (name == "INTERPOLATED").then(|| "from-env".to_string())
+    }
+
+    #[test]
+    fn references_are_interpolated() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        write(&secret, "from-file\n").unwrap();
+        let s = format!(
+            "${{env:INTERPOLATED}}/${{file:{}}}/${{base64:aGk=}}",
+            secret.display()
+        );
+        assert_eq!(
+            interpolate_with("key", &s, &env).unwrap(),
+            "from-env/from-file/hi"
+        );
+        assert_eq!(
+            interpolate_with("key", "plain $x {y}", &env).unwrap(),
+            "plain $x {y}"
+        );
+    }
+
+    #[test]
+    fn doubled_dollars_are_literal() {
+        assert_eq!(
+            interpolate_with("key", "$${env:INTERPOLATED}", &env).unwrap(),
+            "${env:INTERPOLATED}"
+        );
+        assert_eq!(
+            interpolate_with("key", "$${x} ${env:INTERPOLATED} $$ $${", &env).unwrap(),
+            "${x} from-env $$ ${"
+        );
+    }
+
+    #[test]
+    fn interpolation_errors_name_the_key() {
+        let err = interpolate_with("auth.token", "${env:UNSET}", &env).unwrap_err();
+        assert_eq!(err.to_string(), "auth.token: env var UNSET is not set");
+        assert!(interpolate_with("key", "${base64:!!}", &env).is_err());
     }
 }
@@ -1,30 +1,45 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env::VarError;
+use std::ffi::OsString;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Weak};
 
-use minijinja::Value as JinjaValue;
+use crate::config::{deserialize_duration, deserialize_optional_duration, TemplateContextConfig};
+use crate::envoy_types::DiscoveryRequest;
+use crate::git::resolve;
+use crate::health::{HealthMap, ItemHealth};
+use crate::sources::block_on;
+use crate::sources::exec::Exec;
+use dashmap::DashMap;
+use futures::future::join_all;
+use minijinja::{Environment, Value as JinjaValue};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 #[cfg(feature = "s3")]
 use rusoto_s3::S3;
 use serde::{de, Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use serde_json::Value as YamlValue;
+#[cfg(feature = "s3")]
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::sync::OnceCell;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::warn;
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum DeserializeAs {
+    #[default]
     Json,
     Yaml,
     Plaintext,
 }
 
-impl Default for DeserializeAs {
-    fn default() -> Self {
-        Self::Json
-    }
-}
-
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub enum Parsed {
@@ -43,7 +58,7 @@ impl From<Parsed> for JinjaValue {
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
-enum DataSource {
+pub enum DataSource {
     File {
         path: String,
     },
@@ -52,9 +67,21 @@ enum DataSource {
         #[serde(deserialize_with = "deserialize_headermap")]
         headers: Option<HeaderMap>,
     },
+    /// Either one variable, or every variable starting with a prefix as a map
     Env {
-        variable: String,
+        #[serde(default)]
+        variable: Option<String>,
+        /// Keys are the rest of each name, lowercased, so `APP_DB_HOST` is `db_host` under `APP_`
+        #[serde(default)]
+        prefix: Option<String>,
+        /// Used when `variable` is not set
+        #[serde(default)]
+        default: Option<String>,
+        /// Used for keys under `prefix` that are not set
+        #[serde(default)]
+        defaults: BTreeMap<String, String>,
     },
+    Exec(Exec),
     #[cfg(feature = "s3")]
     S3 {
         bucket: String,
@@ -90,43 +117,113 @@ pub struct TemplateContext {
     #[serde(default)]
     deserialize_as: DeserializeAs,
     data_source: DataSource,
+    /// Overrides the interval of the whole context
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    interval: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration", default = "default_timeout")]
+    timeout: Duration,
+    /// Extra attempts after a failed load, before the poll counts as failed
+    #[serde(default)]
+    retries: u32,
+    /// The wait before the first retry, doubling for each one after
+    #[serde(deserialize_with = "deserialize_duration", default = "default_backoff")]
+    backoff: Duration,
+    /// Keep serving the last loaded value when a poll fails, instead of dropping it
+    #[serde(default = "default_stale_on_error")]
+    stale_on_error: bool,
 }
 
-impl TemplateContext {
-    pub fn load(&self) -> anyhow::Result<Parsed> {
-        let data: Vec<u8> = match &self.data_source {
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_stale_on_error() -> bool {
+    true
+}
+
+impl DataSource {
+    /// Problems that show without reading the data
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            DataSource::File { path } if !resolve(Path::new(path)).exists() => {
+                Err(format!("{path} does not exist"))
+            }
+            // The URL may hold credentials, so it is not repeated
+            DataSource::Http { url, .. } => url::Url::parse(url)
+                .map(|_| ())
+                .map_err(|e| format!("invalid url: {e}")),
+            DataSource::Env {
+                variable,
+                prefix,
+                default,
+                ..
+            } => match (variable, prefix) {
+                (Some(variable), None) => match (std::env::var_os(variable), default) {
+                    (None, None) => Err(format!("env var {variable} is not set")),
+                    _ => Ok(()),
+                },
+                (None, Some(_)) => Ok(()),
+                _ => Err("an env data source needs one of variable or prefix".into()),
+            },
+            DataSource::Exec(exec) => exec.check(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the data, giving up on requests that outlive `limit`
+    pub fn fetch(&self, limit: Duration) -> anyhow::Result<Vec<u8>> {
+        let data: Vec<u8> = match self {
             DataSource::File { path } => {
-                let mut file = File::open(path)?;
+                let mut file = File::open(resolve(Path::new(path)))?;
                 let mut buffer = Vec::new();
                 file.read_to_end(&mut buffer)?;
                 buffer
             }
-            DataSource::Env { variable } => std::env::var(variable)
-                .expect("Failed to obtain env var: {variable}")
-                .as_bytes()
-                .to_vec(),
+            DataSource::Env {
+                variable,
+                prefix,
+                default,
+                defaults,
+            } => match (variable, prefix) {
+                (Some(variable), None) => match std::env::var(variable) {
+                    Ok(value) => value.into_bytes(),
+                    Err(VarError::NotPresent) => default
+                        .clone()
+                        .ok_or_else(|| anyhow::anyhow!("Env var {variable} is not set"))?
+                        .into_bytes(),
+                    Err(e) => anyhow::bail!("Could not read env var {variable}: {e}"),
+                },
+                (None, Some(prefix)) => serde_json::to_vec(&prefixed_env(
+                    prefix,
+                    defaults,
+                    &DeserializeAs::Plaintext,
+                    std::env::vars_os(),
+                )?)?,
+                _ => anyhow::bail!("An env data source needs one of variable or prefix"),
+            },
             DataSource::Http { url, headers } => {
                 let u = url.clone();
                 let h = headers.clone();
-                let future = async {
+                let future = async move {
                     let client = reqwest::Client::new();
                     client
                         .get(u)
                         .headers(h.unwrap_or_default())
+                        .timeout(limit)
                         .send()
+                        .await?
+                        .error_for_status()?
+                        .bytes()
                         .await
-                        .unwrap()
-                        .text()
-                        .await
-                        .unwrap()
                 };
-                let handle = tokio::task::spawn(future);
-                let result = tokio::task::block_in_place(|| {
-                    let runtime = tokio::runtime::Handle::current();
-                    runtime.block_on(handle)
-                });
-                result.unwrap().as_bytes().to_vec()
+                // The URL may hold credentials, and errors end up in health output
+                block_on(future)?.map_err(|e| e.without_url())?.to_vec()
             }
+            DataSource::Exec(exec) => exec.run()?,
             #[cfg(feature = "s3")]
             DataSource::S3 {
                 bucket,
@@ -140,17 +237,25 @@ impl TemplateContext {
                     key: key.clone(),
                     ..Default::default()
                 };
-                let rt = tokio::runtime::Runtime::new()?;
-                let future = s3_client.get_object(get_req);
-                let result = rt.block_on(future)?;
-                let stream = result.body.unwrap();
-                let mut buffer = Vec::new();
-                stream.into_blocking_read().read_to_end(&mut buffer)?;
-                buffer
+                let future = async move {
+                    let result = s3_client.get_object(get_req).await?;
+                    let mut buffer = Vec::new();
+                    if let Some(body) = result.body {
+                        body.into_async_read().read_to_end(&mut buffer).await?;
+                    }
+                    anyhow::Ok(buffer)
+                };
+                block_on(timeout(limit, future))?
+                    .map_err(|_| anyhow::anyhow!("Timed out after {limit:?}"))??
             }
         };
+        Ok(data)
+    }
+}
 
-        let parsed = match &self.deserialize_as {
+impl DeserializeAs {
+    pub fn parse(&self, data: Vec<u8>) -> anyhow::Result<Parsed> {
+        let parsed = match self {
             DeserializeAs::Json => {
                 let json: JsonValue = serde_json::from_slice(&data)?;
                 Parsed::Structured(json)
@@ -164,15 +269,403 @@ impl TemplateContext {
                 Parsed::Text(text)
             }
         };
-
         Ok(parsed)
     }
 }
 
-pub fn poll_context(ctx: &HashMap<String, TemplateContext>) -> JinjaValue {
-    let map: HashMap<String, Parsed> = ctx
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.load().unwrap()))
-        .collect();
-    JinjaValue::from(map)
+/// The env vars starting with `prefix`, keyed by the rest of their name in lowercase.
+/// Each one is parsed on its own, keeping the raw string for any that don't parse.
+fn prefixed_env(
+    prefix: &str,
+    defaults: &BTreeMap<String, String>,
+    deserialize_as: &DeserializeAs,
+    env: impl Iterator<Item = (OsString, OsString)>,
+) -> anyhow::Result<JsonValue> {
+    let mut vars = defaults.clone();
+    // Names or values that aren't unicode can't be templated, so they are skipped
+    vars.extend(env.filter_map(|(name, value)| {
+        let key = name.to_str()?.strip_prefix(prefix)?.to_lowercase();
+        Some((key, value.into_string().ok()?))
+    }));
+    if vars.is_empty() {
+        anyhow::bail!("No env vars start with {prefix}");
+    }
+    let map = vars.into_iter().map(|(key, value)| {
+        let value = match deserialize_as.parse(value.clone().into_bytes()) {
+            Ok(Parsed::Structured(value)) => value,
+            Ok(Parsed::Text(_)) | Err(_) => JsonValue::String(value),
+        };
+        (key, value)
+    });
+    Ok(JsonValue::Object(map.collect()))
+}
+
+impl TemplateContext {
+    /// Problems that show without loading the item
+    pub fn check(&self) -> Result<(), String> {
+        self.data_source.check()
+    }
+
+    pub fn load(&self) -> anyhow::Result<Parsed> {
+        match &self.data_source {
+            DataSource::Env {
+                prefix: Some(prefix),
+                variable: None,
+                defaults,
+                ..
+            } => Ok(Parsed::Structured(prefixed_env(
+                prefix,
+                defaults,
+                &self.deserialize_as,
+                std::env::vars_os(),
+            )?)),
+            source => self.deserialize_as.parse(source.fetch(self.timeout)?),
+        }
+    }
+}
+
+impl TemplateContext {
+    /// Loads the item off the async runtime, within its timeout
+    pub async fn load_once(&self) -> anyhow::Result<Parsed> {
+        let item = self.clone();
+        let loaded = timeout(
+            self.timeout,
+            tokio::task::spawn_blocking(move || item.load()),
+        );
+        match loaded.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow::anyhow!("Timed out after {:?}", self.timeout)),
+        }
+    }
+
+    /// Loads the item like `load_once`, retrying with backoff
+    pub async fn load_with_retries(&self) -> anyhow::Result<Parsed> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.load_once().await {
+                Err(_) if attempt < self.retries => {
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The latest value and health of every context item
+pub struct ContextStore {
+    values: DashMap<String, Parsed>,
+    health: HealthMap,
+    tx: Sender<JinjaValue>,
+    /// One per polling task, which holds the only strong reference until it stops
+    pollers: Vec<Weak<()>>,
+}
+
+impl ContextStore {
+    fn record(&self, name: &str, item: &TemplateContext, result: anyhow::Result<Parsed>) {
+        self.health.record(name, &result);
+        match result {
+            Ok(parsed) => {
+                self.values.insert(name.to_string(), parsed);
+            }
+            Err(e) => {
+                warn!(item = name, "Could not load template context: {e}");
+                if !item.stale_on_error {
+                    self.values.remove(name);
+                }
+            }
+        }
+    }
+
+    fn publish(&self) {
+        let map: HashMap<String, Parsed> = self
+            .values
+            .iter()
+            .map(|v| (v.key().clone(), v.value().clone()))
+            .collect();
+        _ = self.tx.send(JinjaValue::from(map));
+    }
+
+    pub fn health(&self) -> BTreeMap<String, ItemHealth> {
+        self.health.snapshot()
+    }
+
+    /// Whether every item is still being polled
+    pub fn running(&self) -> bool {
+        self.pollers.iter().all(|poller| poller.strong_count() > 0)
+    }
+}
+
+/// Loads every item concurrently, then keeps reloading each one on its own interval
+pub async fn watch_context(
+    config: TemplateContextConfig,
+    tasks: &mut JoinSet<()>,
+) -> (Receiver<JinjaValue>, Arc<ContextStore>) {
+    let (tx, rx) = watch::channel(JinjaValue::from(HashMap::<String, Parsed>::new()));
+    let running: Vec<Arc<()>> = config.items.iter().map(|_| Arc::new(())).collect();
+    let store = Arc::new(ContextStore {
+        values: DashMap::new(),
+        health: HealthMap::default(),
+        tx,
+        pollers: running.iter().map(Arc::downgrade).collect(),
+    });
+
+    let mut initial = JoinSet::new();
+    for (name, item) in config.items.clone() {
+        initial.spawn(async move {
+            let result = item.load_with_retries().await;
+            (name, item, result)
+        });
+    }
+    let mut pending: HashMap<String, TemplateContext> = config.items.clone();
+    while let Some(joined) = initial.join_next().await {
+        match joined {
+            Ok((name, item, result)) => {
+                pending.remove(&name);
+                store.record(&name, &item, result);
+            }
+            Err(e) => warn!("Template context task failed: {e}"),
+        }
+    }
+    // A task that panicked can't say which item it was loading
+    for (name, item) in pending {
+        store.record(
+            &name,
+            &item,
+            Err(anyhow::anyhow!("Loading stopped unexpectedly")),
+        );
+    }
+    store.publish();
+
+    for ((name, item), running) in config.items.into_iter().zip(running) {
+        let store = store.clone();
+        let interval = item.interval.unwrap_or(config.interval);
+        tasks.spawn(async move {
+            let _running = running;
+            loop {
+                sleep(interval).await;
+                let result = item.load_with_retries().await;
+                store.record(&name, &item, result);
+                store.publish();
+            }
+        });
+    }
+    (rx, store)
+}
+
+/// Context fetched per request, from a URL templated with the request's fields
+#[derive(Deserialize, Clone)]
+pub struct NodeContext {
+    /// e.g. `https://api/limits/{{ node.cluster }}`
+    url: String,
+    #[serde(default, deserialize_with = "deserialize_headermap")]
+    headers: Option<HeaderMap>,
+    #[serde(default)]
+    deserialize_as: DeserializeAs,
+    /// How long a fetched value is reused for
+    #[serde(deserialize_with = "deserialize_duration", default = "default_ttl")]
+    ttl: Duration,
+    #[serde(deserialize_with = "deserialize_duration", default = "default_timeout")]
+    timeout: Duration,
+}
+
+fn default_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// How long a failed fetch is remembered before it's retried
+const FAILURE_TTL: Duration = Duration::from_secs(5);
+/// Beyond this many cached values, new URLs are fetched without being cached
+const MAX_NODE_CONTEXT_ENTRIES: usize = 10_000;
+/// How often expired values are dropped from the cache
+pub const NODE_CONTEXT_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Characters left as they are when node fields are rendered into a URL
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A fetched value and when it expires, or `None` if the fetch failed
+type Fetched = (Instant, Option<Parsed>);
+
+/// Fetches per-node context on demand, sharing fetches between concurrent requests
+pub struct NodeContextCache {
+    items: HashMap<String, NodeContext>,
+    client: reqwest::Client,
+    env: Environment<'static>,
+    cache: DashMap<(String, String), Arc<OnceCell<Fetched>>>,
+}
+
+impl NodeContext {
+    async fn fetch(&self, client: &reqwest::Client, url: &str) -> anyhow::Result<Parsed> {
+        let request = client
+            .get(url)
+            .headers(self.headers.clone().unwrap_or_default())
+            .send();
+        let data = timeout(self.timeout, async {
+            request.await?.error_for_status()?.bytes().await
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", self.timeout))?
+        .map_err(|e| e.without_url())?;
+        self.deserialize_as.parse(data.to_vec())
+    }
+}
+
+impl NodeContextCache {
+    pub fn new(items: HashMap<String, NodeContext>) -> Self {
+        let mut env = Environment::new();
+        // Node fields come from the client, so they can't add path segments or query params
+        env.set_formatter(|out, _, value| {
+            if !value.is_undefined() && !value.is_none() {
+                let value = value.to_string();
+                write!(out, "{}", utf8_percent_encode(&value, URL_COMPONENT))?;
+            }
+            Ok(())
+        });
+        Self {
+            items,
+            client: reqwest::Client::new(),
+            env,
+            cache: DashMap::new(),
+        }
+    }
+
+    /// Drops every value that has expired
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.cache
+            .retain(|_, slot| slot.get().is_none_or(|(expires, _)| now < *expires));
+    }
+
+    async fn value(
+        &self,
+        name: &str,
+        item: &NodeContext,
+        request: &DiscoveryRequest,
+    ) -> Option<Parsed> {
+        let url = match self.env.render_str(&item.url, request) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(item = name, "Could not render context url: {e}");
+                return None;
+            }
+        };
+        let expired = |fetched: &Fetched| Instant::now() >= fetched.0;
+        let key = (name.to_string(), url.clone());
+        let slot = if self.cache.contains_key(&key) || self.cache.len() < MAX_NODE_CONTEXT_ENTRIES {
+            let mut entry = self
+                .cache
+                .entry(key)
+                .or_insert_with(|| Arc::new(OnceCell::new()));
+            // An unset slot is being fetched by another request, which this one waits for
+            if entry.get().is_some_and(expired) {
+                *entry = Arc::new(OnceCell::new());
+            }
+            entry.clone()
+        } else {
+            Arc::new(OnceCell::new())
+        };
+        let (_, value) = slot
+            .get_or_init(|| async {
+                let value = item.fetch(&self.client, &url).await;
+                if let Err(e) = &value {
+                    warn!(item = name, "Could not fetch context: {e}");
+                }
+                let ttl = match value {
+                    Ok(_) => item.ttl,
+                    Err(_) => FAILURE_TTL.min(item.ttl),
+                };
+                (Instant::now() + ttl, value.ok())
+            })
+            .await;
+        value.clone()
+    }
+
+    /// Every item for the node that made this request, leaving out any that failed
+    pub async fn values(&self, request: &DiscoveryRequest) -> HashMap<String, Parsed> {
+        let lookups = self.items.iter().map(|(name, item)| async move {
+            Some((name.clone(), self.value(name, item, request).await?))
+        });
+        join_all(lookups).await.into_iter().flatten().collect()
+    }
+}
+
+/// Loads every item once, concurrently, failing if any of them fail
+pub async fn poll_context(items: &HashMap<String, TemplateContext>) -> anyhow::Result<JinjaValue> {
+    let mut loading = JoinSet::new();
+    for (name, item) in items.clone() {
+        loading.spawn(async move { (item.load_with_retries().await, name) });
+    }
+    let mut map: HashMap<String, Parsed> = HashMap::new();
+    while let Some(loaded) = loading.join_next().await {
+        let (result, name) = loaded?;
+        let parsed = result.map_err(|e| anyhow::anyhow!("Could not load {name}: {e}"))?;
+        map.insert(name, parsed);
+    }
+    Ok(JinjaValue::from(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cache(url: &str) -> NodeContextCache {
+        let item: NodeContext =
+            serde_json::from_value(json!({"url": url, "timeout": 1, "ttl": 60})).unwrap();
+        NodeContextCache::new(HashMap::from([("limits".to_string(), item)]))
+    }
+
+    #[test]
+    fn prefixed_env_keeps_values_that_dont_parse() {
+        // The process environment can't safely be changed while other tests read it
+        let vars = || {
+            [
+                ("APP_PORTS", "[80, 443]"),
+                ("APP_HOST", "example.com"),
+                ("OTHER", "x"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+        };
+        let defaults = BTreeMap::from([("region".to_string(), "\"local\"".to_string())]);
+        let env = prefixed_env("APP_", &defaults, &DeserializeAs::Json, vars()).unwrap();
+        assert_eq!(
+            env,
+            json!({"ports": [80, 443], "host": "example.com", "region": "local"})
+        );
+        let env = prefixed_env("APP_", &defaults, &DeserializeAs::Plaintext, vars());
+        assert_eq!(env.unwrap()["ports"], json!("[80, 443]"));
+        assert!(prefixed_env("UNSET_", &BTreeMap::new(), &DeserializeAs::Json, vars()).is_err());
+    }
+
+    #[test]
+    fn node_fields_are_encoded_in_urls() {
+        let request = DiscoveryRequest::new("a/../b?x=1 y".into(), "1.25.0".into(), None);
+        let url = cache("")
+            .env
+            .render_str("https://api/limits/{{ node.cluster }}?v=1", &request)
+            .unwrap();
+        assert_eq!(url, "https://api/limits/a%2F..%2Fb%3Fx%3D1%20y?v=1");
+    }
+
+    #[tokio::test]
+    async fn failures_are_cached_briefly() {
+        let cache = cache("http://127.0.0.1:1/{{ node.cluster }}");
+        let request = DiscoveryRequest::new("edge".into(), "1.25.0".into(), None);
+        assert!(cache.values(&request).await.is_empty());
+        let key = ("limits".to_string(), "http://127.0.0.1:1/edge".to_string());
+        let (expires, value) = cache.cache.get(&key).unwrap().get().cloned().unwrap();
+        assert!(value.is_none());
+        assert!(expires <= Instant::now() + FAILURE_TTL);
+        cache.sweep();
+        assert_eq!(cache.cache.len(), 1);
+    }
 }
@@ -1,6 +1,9 @@
+use regex::Regex;
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use xxhash_rust::xxh64::xxh64;
 
 #[derive(Serialize)]
@@ -21,9 +24,9 @@ impl DiscoveryResponse {
 
 #[derive(Serialize, Deserialize)]
 struct SemanticVersion {
-    major_number: u8,
-    minor_number: u8,
-    patch: u8,
+    major_number: u64,
+    minor_number: u64,
+    patch: u64,
 }
 
 impl std::fmt::Display for SemanticVersion {
@@ -40,11 +43,11 @@ struct BuildVersion {
     version: SemanticVersion,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Locality {
-    region: Option<String>,
-    zone: Option<String>,
-    sub_zone: Option<String>,
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Locality {
+    pub region: Option<String>,
+    pub zone: Option<String>,
+    pub sub_zone: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -86,17 +89,43 @@ impl DiscoveryRequest {
         }
     }
 
-    pub fn envoy_version(&self) -> String {
+    pub fn with_node_id(mut self, id: String) -> Self {
+        self.node.id = Some(id);
+        self
+    }
+
+    pub fn with_locality(mut self, locality: Locality) -> Self {
+        self.node.locality = Some(locality);
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: HashMap<String, JsonValue>) -> Self {
+        self.node.metadata = metadata;
+        self
+    }
+
+    /// The Envoy version of the node, ignoring `-dev` and distribution suffixes
+    pub fn envoy_version(&self) -> anyhow::Result<Version> {
         if let Some(v) = &self.node.build_version {
-            if let Some(version) = v.split("/").into_iter().nth(1) {
-                return version.to_string();
-            } else {
-                panic!("Could not parse envoy build version: {v}")
-            }
+            // Build versions look like `<sha>/1.25.0-dev/Clean/RELEASE/BoringSSL`,
+            // so the version is the first segment that starts with one
+            static PATTERN: OnceLock<Regex> = OnceLock::new();
+            let pattern = PATTERN.get_or_init(|| Regex::new(r"^(\d+)\.(\d+)(?:\.(\d+))?").unwrap());
+            v.split('/')
+                .find_map(|segment| pattern.captures(segment))
+                .and_then(|c| {
+                    let part = |i| c.get(i).map_or(Some(0), |m| m.as_str().parse().ok());
+                    Some(Version::new(part(1)?, part(2)?, part(3)?))
+                })
+                .ok_or_else(|| anyhow::anyhow!("Could not parse envoy build version: {v}"))
         } else if let Some(v) = &self.node.user_agent_build_version {
-            v.version.to_string()
+            Ok(Version::new(
+                v.version.major_number,
+                v.version.minor_number,
+                v.version.patch,
+            ))
         } else {
-            panic!("No envoy version")
+            anyhow::bail!("No envoy version")
         }
     }
 
@@ -104,7 +133,62 @@ impl DiscoveryRequest {
         &self.node.cluster
     }
 
+    pub fn node_id(&self) -> Option<&str> {
+        self.node.id.as_deref()
+    }
+
+    pub fn locality(&self) -> Option<&Locality> {
+        self.node.locality.as_ref()
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, JsonValue> {
+        &self.node.metadata
+    }
+
     pub fn resource_names(&self) -> Vec<String> {
         self.resource_names.to_owned().unwrap_or_default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(build_version: &str) -> anyhow::Result<Version> {
+        DiscoveryRequest::new("c".to_string(), build_version.to_string(), None).envoy_version()
+    }
+
+    #[test]
+    fn build_versions_are_parsed() {
+        let parsed = |raw| version(raw).unwrap().to_string();
+        assert_eq!(
+            parsed("e5f864a82d4f27110359daa2fbdcb12d99e415b9/1.25.0/Clean/RELEASE/BoringSSL"),
+            "1.25.0"
+        );
+        assert_eq!(parsed("abc/1.26.3-dev/Modified/DEBUG/BoringSSL"), "1.26.3");
+        assert_eq!(parsed("abc/1.24/Clean/RELEASE"), "1.24.0");
+        assert_eq!(parsed("1.27.1"), "1.27.1");
+    }
+
+    #[test]
+    fn unparseable_build_versions_are_errors() {
+        assert!(version("").is_err());
+        assert!(version("abc/unknown/Clean").is_err());
+        assert!(version("abc/99999999999999999999.1.0/Clean").is_err());
+    }
+
+    #[test]
+    fn user_agent_versions_are_used_without_a_build_version() {
+        let request: DiscoveryRequest = serde_json::from_value(serde_json::json!({
+            "node": {
+                "cluster": "c",
+                "metadata": {},
+                "user_agent_build_version": {
+                    "version": {"major_number": 1, "minor_number": 28, "patch": 2}
+                }
+            }
+        }))
+        .unwrap();
+        assert_eq!(request.envoy_version().unwrap().to_string(), "1.28.2");
+    }
+}
@@ -0,0 +1,224 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::{json, Value as JsonValue};
+use tracing::error;
+
+/// Everything that can go wrong while answering a discovery request
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// The resource path isn't of the form `discovery:<resource type>`
+    InvalidResource { resource: String },
+    /// No configured credential was presented
+    Unauthenticated,
+    /// The credential may not request configuration for the node's cluster
+    Forbidden { cluster: String },
+    /// The node did not send an Envoy version that could be parsed
+    InvalidVersion {
+        resource_type: String,
+        message: String,
+    },
+    TemplateNotFound {
+        resource_type: String,
+        version: String,
+    },
+    /// The template file could not be read
+    TemplateSource {
+        template: String,
+        resource_type: String,
+        message: String,
+    },
+    Render {
+        template: String,
+        resource_type: String,
+        message: String,
+    },
+    /// The rendered output could not be parsed, with the lines around the failure
+    Deserialize {
+        template: String,
+        resource_type: String,
+        message: String,
+        excerpt: Option<String>,
+    },
+}
+
+impl DiscoveryError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DiscoveryError::InvalidResource { .. } | DiscoveryError::InvalidVersion { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            DiscoveryError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            DiscoveryError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            DiscoveryError::TemplateNotFound { .. } => StatusCode::NOT_FOUND,
+            DiscoveryError::TemplateSource { .. }
+            | DiscoveryError::Render { .. }
+            | DiscoveryError::Deserialize { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            DiscoveryError::InvalidResource { .. } => "invalid_resource",
+            DiscoveryError::InvalidVersion { .. } => "invalid_version",
+            DiscoveryError::Unauthenticated => "unauthenticated",
+            DiscoveryError::Forbidden { .. } => "forbidden",
+            DiscoveryError::TemplateNotFound { .. } => "template_not_found",
+            DiscoveryError::TemplateSource { .. } => "template_source",
+            DiscoveryError::Render { .. } => "render",
+            DiscoveryError::Deserialize { .. } => "deserialize",
+        }
+    }
+
+    pub fn template(&self) -> Option<&str> {
+        match self {
+            DiscoveryError::TemplateSource { template, .. }
+            | DiscoveryError::Render { template, .. }
+            | DiscoveryError::Deserialize { template, .. } => Some(template),
+            _ => None,
+        }
+    }
+
+    pub fn resource_type(&self) -> Option<&str> {
+        match self {
+            DiscoveryError::InvalidResource { .. }
+            | DiscoveryError::Unauthenticated
+            | DiscoveryError::Forbidden { .. } => None,
+            DiscoveryError::InvalidVersion { resource_type, .. }
+            | DiscoveryError::TemplateNotFound { resource_type, .. }
+            | DiscoveryError::TemplateSource { resource_type, .. }
+            | DiscoveryError::Render { resource_type, .. }
+            | DiscoveryError::Deserialize { resource_type, .. } => Some(resource_type),
+        }
+    }
+}
+
+impl DiscoveryError {
+    pub fn body(&self) -> JsonValue {
+        let mut body = json!({
+            "code": self.code(),
+            "message": self.to_string(),
+            "template": self.template(),
+            "resource_type": self.resource_type(),
+        });
+        if let DiscoveryError::Deserialize {
+            excerpt: Some(excerpt),
+            ..
+        } = self
+        {
+            body["excerpt"] = json!(excerpt);
+        }
+        body
+    }
+}
+
+impl std::fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscoveryError::InvalidResource { resource } => {
+                write!(
+                    f,
+                    "Expected a resource of the form discovery:<type>, got {resource}"
+                )
+            }
+            DiscoveryError::Unauthenticated => f.write_str("No valid credential was presented"),
+            DiscoveryError::Forbidden { cluster } => {
+                write!(f, "Credential may not request configuration for {cluster}")
+            }
+            DiscoveryError::TemplateNotFound {
+                resource_type,
+                version,
+            } => write!(f, "No configuration found for {resource_type}:{version}"),
+            DiscoveryError::InvalidVersion { message, .. }
+            | DiscoveryError::TemplateSource { message, .. }
+            | DiscoveryError::Render { message, .. }
+            | DiscoveryError::Deserialize { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+impl IntoResponse for DiscoveryError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!(
+                code = self.code(),
+                template = ?self.template(),
+                resource_type = ?self.resource_type(),
+                "{self}"
+            );
+        }
+        (status, Json(self.body())).into_response()
+    }
+}
+
+/// The lines surrounding a YAML error, with a caret under the failing column
+pub fn yaml_excerpt(error: &serde_yaml::Error, content: &str) -> Option<String> {
+    let location = error.location()?;
+    let line = location.line();
+    let column = location.column();
+
+    let start = line.saturating_sub(5).max(1);
+    let end = line + 5;
+
+    let mut excerpt = vec![];
+    let lines = content
+        .split('\n')
+        .enumerate()
+        // Start index from 1
+        .map(|(i, txt)| (i + 1, txt));
+    for (idx, text) in lines {
+        if idx >= start && idx <= end {
+            excerpt.push(format!("{}: {}", idx, text));
+            if idx == line {
+                // Columns start from 1, after the `idx: ` prefix
+                let offset = idx.to_string().len() + 2 + column.saturating_sub(1);
+                excerpt.push(format!("{}^", " ".repeat(offset)));
+            }
+        }
+    }
+    Some(excerpt.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excerpt(content: &str) -> String {
+        let error = serde_yaml::from_str::<serde_yaml::Value>(content).unwrap_err();
+        yaml_excerpt(&error, content).unwrap()
+    }
+
+    #[test]
+    fn excerpt_points_at_the_failing_column() {
+        let content = "name: a\nroutes: [b\nport: 80\n";
+        let error = serde_yaml::from_str::<serde_yaml::Value>(content).unwrap_err();
+        let location = error.location().unwrap();
+        let lines: Vec<String> = excerpt(content).lines().map(str::to_string).collect();
+        let failing = lines
+            .iter()
+            .position(|l| l.starts_with(&format!("{}: ", location.line())))
+            .unwrap();
+        let caret = &lines[failing + 1];
+        assert_eq!(caret.trim(), "^");
+        let prefix = format!("{}: ", location.line()).len();
+        assert_eq!(caret.len() - 1, prefix + location.column() - 1);
+    }
+
+    #[test]
+    fn excerpt_keeps_five_lines_either_side() {
+        let mut lines: Vec<String> = (1..=20).map(|i| format!("k{i}: {i}")).collect();
+        lines[11] = "k12: [".to_string();
+        let content = lines.join("\n");
+        let error = serde_yaml::from_str::<serde_yaml::Value>(&content).unwrap_err();
+        let line = error.location().unwrap().line();
+        let numbered: Vec<usize> = excerpt(&content)
+            .lines()
+            .filter_map(|l| l.split_once(": ")?.0.parse().ok())
+            .collect();
+        let expected: Vec<usize> = (line.saturating_sub(5).max(1)..=(line + 5).min(20)).collect();
+        assert_eq!(numbered, expected);
+    }
+}
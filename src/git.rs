@@ -0,0 +1,307 @@
+use crate::config::deserialize_duration;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+use tokio::sync::watch::{self, Receiver};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+
+/// The checkout that relative file paths are read from, once a repository is configured
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Resolves a configured path, reading relative paths from the git checkout if there is one
+pub fn resolve(path: &Path) -> PathBuf {
+    match ROOT.get() {
+        Some(root) if path.is_relative() => root.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GitConfig {
+    /// A local path or `file://` URL
+    pub repository: String,
+    /// A branch, tag or commit
+    #[serde(rename = "ref", default = "default_ref")]
+    pub reference: String,
+    /// Where the mirror and checkouts are kept
+    #[serde(default = "default_checkout_dir")]
+    pub checkout_dir: PathBuf,
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        default = "default_interval"
+    )]
+    pub interval: Duration,
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Takes an exclusive lock on a file, shared with other processes, held until the file is closed
+fn lock(path: &Path) -> std::io::Result<File> {
+    let file = File::create(path)?;
+    loop {
+        // SAFETY: the descriptor belongs to `file`, which outlives the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(file);
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+fn default_ref() -> String {
+    "HEAD".to_string()
+}
+
+fn default_checkout_dir() -> PathBuf {
+    std::env::temp_dir().join("sovereign-git")
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// The commit currently being served
+#[derive(Serialize, Clone, Debug)]
+pub struct GitStatus {
+    pub repository: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub commit: String,
+    /// Seconds since the Unix epoch
+    pub updated: u64,
+}
+
+impl GitConfig {
+    fn mirror(&self) -> PathBuf {
+        self.checkout_dir.join("mirror.git")
+    }
+
+    /// A symlink to the checkout of the current commit, swapped atomically on update
+    pub fn current(&self) -> PathBuf {
+        self.checkout_dir.join("current")
+    }
+
+    fn git(&self, args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.mirror())
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// The commit the `current` symlink points at, if any
+    fn checked_out(&self) -> Option<String> {
+        let target = std::fs::read_link(self.current()).ok()?;
+        Some(target.file_name()?.to_string_lossy().to_string())
+    }
+
+    /// Fetches the repository and checks out the ref, returning the commit being served
+    pub fn sync(&self) -> anyhow::Result<String> {
+        std::fs::create_dir_all(self.checkout_dir.join("checkouts"))?;
+        // Other processes may share the checkout dir, and git can't fetch into a mirror twice at once
+        let _lock = lock(&self.checkout_dir.join("lock"))?;
+        if self.mirror().exists() {
+            self.git(&["fetch", "--prune", "--force", "origin"])?;
+        } else {
+            let output = Command::new("git")
+                .args(["clone", "--mirror", "--quiet", &self.repository])
+                .arg(self.mirror())
+                .output()?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "Could not clone {}: {}",
+                    self.repository,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+
+        let commit = self.git(&[
+            "rev-parse",
+            "--verify",
+            &format!("{}^{{commit}}", self.reference),
+        ])?;
+        let previous = self.checked_out();
+        if previous.as_deref() == Some(commit.as_str()) {
+            return Ok(commit);
+        }
+
+        let checkout = self.checkout_dir.join("checkouts").join(&commit);
+        if checkout.exists() {
+            std::fs::remove_dir_all(&checkout)?;
+            self.git(&["worktree", "prune"])?;
+        }
+        self.git(&[
+            "worktree",
+            "add",
+            "--detach",
+            "--force",
+            &checkout.to_string_lossy(),
+            &commit,
+        ])?;
+
+        // Renaming a new symlink over the old one swaps every path at once
+        let staged = self.checkout_dir.join("current.new");
+        _ = std::fs::remove_file(&staged);
+        symlink(&Path::new("checkouts").join(&commit), &staged)?;
+        std::fs::rename(&staged, self.current())?;
+
+        // Reads that started before the swap may still be using the previous checkout,
+        // so it's kept until the next one
+        for entry in std::fs::read_dir(self.checkout_dir.join("checkouts"))? {
+            let old = entry?.path();
+            let name = old.file_name().map(|n| n.to_string_lossy().to_string());
+            if name == Some(commit.clone()) || name == previous {
+                continue;
+            }
+            if let Err(e) = self.git(&["worktree", "remove", "--force", &old.to_string_lossy()]) {
+                warn!("Could not remove old checkout: {e}");
+            }
+        }
+        Ok(commit)
+    }
+
+    fn status(&self, commit: String) -> GitStatus {
+        GitStatus {
+            repository: self.repository.clone(),
+            reference: self.reference.clone(),
+            commit,
+            updated: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Checks out the repository and makes relative paths read from it
+    pub fn checkout(&self) -> anyhow::Result<String> {
+        let commit = self.sync()?;
+        info!(repository = %self.repository, commit = %commit, "Checked out git repository");
+        _ = ROOT.set(self.current());
+        Ok(commit)
+    }
+
+    /// Checks out the repository into a directory of its own, which lasts as long as the
+    /// returned handle, for commands that run alongside a server using the shared checkout
+    pub fn checkout_private(&self) -> anyhow::Result<TempDir> {
+        let dir = tempfile::Builder::new()
+            .prefix("sovereign-git-")
+            .tempdir()?;
+        let private = GitConfig {
+            checkout_dir: dir.path().to_path_buf(),
+            ..self.clone()
+        };
+        private.checkout()?;
+        Ok(dir)
+    }
+
+    /// Checks out the repository and keeps it up to date
+    pub fn watch(self, tasks: &mut JoinSet<()>) -> anyhow::Result<Receiver<GitStatus>> {
+        let commit = self.checkout()?;
+
+        let (tx, rx) = watch::channel(self.status(commit));
+        tasks.spawn(async move {
+            loop {
+                sleep(self.interval).await;
+                let config = self.clone();
+                match tokio::task::spawn_blocking(move || config.sync()).await {
+                    Ok(Ok(commit)) if commit != tx.borrow().commit => {
+                        info!(repository = %self.repository, commit = %commit, "Checked out git repository");
+                        _ = tx.send(self.status(commit));
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        warn!(repository = %self.repository, "Could not update git repository: {e}")
+                    }
+                    Err(e) => {
+                        warn!(repository = %self.repository, "Could not update git repository: {e}")
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(repo: &Path, content: &str) {
+        std::fs::write(repo.join("config.yaml"), content).unwrap();
+        for args in [&["add", "-A"][..], &["commit", "-qm", content]] {
+            let status = Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(repo)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn previous_checkout_is_kept_until_the_next_swap() {
+        let repo = tempfile::tempdir().unwrap();
+        let status = Command::new("git")
+            .args(["init", "-q"])
+            .arg(repo.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let checkouts = tempfile::tempdir().unwrap();
+        let config = GitConfig {
+            repository: repo.path().to_string_lossy().to_string(),
+            reference: default_ref(),
+            checkout_dir: checkouts.path().to_path_buf(),
+            interval: default_interval(),
+        };
+        let checked_out = || {
+            let mut names: Vec<String> = std::fs::read_dir(checkouts.path().join("checkouts"))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        commit(repo.path(), "one");
+        let first = config.sync().unwrap();
+        commit(repo.path(), "two");
+        let second = config.sync().unwrap();
+        let mut both = vec![first.clone(), second.clone()];
+        both.sort();
+        assert_eq!(checked_out(), both);
+        assert_eq!(
+            std::fs::read_to_string(config.current().join("config.yaml")).unwrap(),
+            "two"
+        );
+
+        commit(repo.path(), "three");
+        let third = config.sync().unwrap();
+        let mut latest = vec![second, third];
+        latest.sort();
+        assert_eq!(checked_out(), latest);
+    }
+}
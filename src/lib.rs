@@ -1,6 +1,16 @@
 pub mod app;
+pub mod auth;
 pub mod config;
 pub mod context;
+pub mod envoy_types;
+pub mod error;
+pub mod git;
+pub mod health;
+pub mod listeners;
+pub mod matching;
+pub mod secrets;
 pub mod sources;
 pub mod templates;
-pub mod envoy_types;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod validate;
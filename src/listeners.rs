@@ -0,0 +1,301 @@
+use crate::config::deserialize_duration;
+use axum::Router;
+use hyper::server::conn::Http;
+use serde::{de, Deserialize};
+use std::future::{pending, Future};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch::{self, Receiver};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, info, warn};
+
+/// Where a listener accepts connections: `host:port`, or `unix:<path>` for a Unix domain socket
+#[derive(Clone, Debug)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => raw.parse().map(ListenAddress::Tcp),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The groups of routes a listener can serve. Both include the health checks.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Routes {
+    /// The discovery endpoints Envoy polls
+    Discovery,
+    /// Status and debug endpoints
+    Admin,
+}
+
+fn all_routes() -> Vec<Routes> {
+    vec![Routes::Discovery, Routes::Admin]
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    #[serde(default = "all_routes")]
+    pub routes: Vec<Routes>,
+    /// Serve TLS when certificates are configured. Unix sockets never do.
+    #[serde(default = "default_tls")]
+    pub tls: bool,
+}
+
+impl ListenerConfig {
+    /// A listener serving every route, as the server runs without any configured
+    pub fn all(address: SocketAddr) -> Self {
+        Self {
+            address: ListenAddress::Tcp(address),
+            routes: all_routes(),
+            tls: true,
+        }
+    }
+
+    pub fn serves(&self, routes: Routes) -> bool {
+        self.routes.contains(&routes)
+    }
+}
+
+/// Binds a Unix domain socket, replacing the socket left behind by a previous run.
+/// Anything else at the path is left alone.
+pub fn bind_unix(path: &PathBuf) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Logs a failed accept, and backs off unless only that connection was affected, like hyper's
+/// `AddrIncoming`. Errors such as running out of file descriptors would otherwise spin the loop.
+pub async fn accept_failed(e: std::io::Error) {
+    use std::io::ErrorKind::{ConnectionAborted, ConnectionRefused, ConnectionReset};
+    if matches!(
+        e.kind(),
+        ConnectionAborted | ConnectionRefused | ConnectionReset
+    ) {
+        debug!("Connection failed before it was accepted: {e}");
+        return;
+    }
+    warn!("Could not accept a connection: {e}");
+    sleep(Duration::from_secs(1)).await;
+}
+
+/// Serves connections until `shutdown`, then waits for their in-flight requests to finish
+pub async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    grace_period: Duration,
+) -> anyhow::Result<()> {
+    let (closing_tx, closing) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    accept_failed(e).await;
+                    continue;
+                }
+            },
+            // Finished connections are reaped as they go, rather than kept until shutdown
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
+        let (app, closing) = (app.clone(), closing.clone());
+        connections.spawn(async move {
+            if let Err(e) = serve_connection(stream, app, closing).await {
+                debug!("Connection closed with an error: {e}");
+            }
+        });
+    }
+    close_connections(closing_tx, connections, grace_period).await;
+    Ok(())
+}
+
+/// Asks every connection to close once its requests are done, and aborts any still open
+/// after `grace_period`
+pub async fn close_connections(
+    closing: watch::Sender<bool>,
+    mut connections: JoinSet<()>,
+    grace_period: Duration,
+) {
+    _ = closing.send(true);
+    let finished = async { while connections.join_next().await.is_some() {} };
+    if timeout(grace_period, finished).await.is_err() {
+        warn!(
+            connections = connections.len(),
+            "Closing connections still open after the grace period"
+        );
+        connections.abort_all();
+    }
+}
+
+/// Serves one connection. Once `closing` is set it finishes the requests in flight and closes,
+/// with a GOAWAY for HTTP/2 clients, so that they reconnect elsewhere.
+pub async fn serve_connection<S>(
+    stream: S,
+    app: Router,
+    mut closing: Receiver<bool>,
+) -> Result<(), hyper::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let connection = Http::new().serve_connection(stream, app);
+    tokio::pin!(connection);
+    tokio::select! {
+        result = connection.as_mut() => return result,
+        _ = closing.changed() => {}
+    }
+    connection.as_mut().graceful_shutdown();
+    connection.await
+}
+
+fn default_drain_period() -> Duration {
+    Duration::from_secs(0)
+}
+
+fn default_grace_period() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ShutdownConfig {
+    /// Seconds to keep serving after SIGTERM with readiness failing,
+    /// so that load balancers stop sending requests first
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        default = "default_drain_period"
+    )]
+    pub drain_period: Duration,
+    /// Seconds in-flight requests get to finish once the listeners stop accepting,
+    /// before their connections are closed
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        default = "default_grace_period"
+    )]
+    pub grace_period: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_period: default_drain_period(),
+            grace_period: default_grace_period(),
+        }
+    }
+}
+
+/// Waits for SIGTERM or Ctrl-C. A signal that can't be listened for is only logged,
+/// so that the server keeps running.
+pub async fn terminated() {
+    let sigterm = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => _ = sigterm.recv().await,
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {e}");
+                pending::<()>().await
+            }
+        }
+    };
+    let interrupt = async {
+        if let Err(e) = ctrl_c().await {
+            warn!("Could not listen for Ctrl-C: {e}");
+            pending::<()>().await
+        }
+    };
+    tokio::select! {
+        _ = sigterm => info!("Received SIGTERM"),
+        _ = interrupt => info!("Received Ctrl-C"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_flight_requests_get_a_grace_period_by_default() {
+        let config: ShutdownConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.drain_period, Duration::ZERO);
+        assert_eq!(config.grace_period, Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn connections_are_aborted_after_the_grace_period() {
+        let (closing_tx, mut closing) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        connections.spawn(async move {
+            _ = closing.changed().await;
+        });
+        connections.spawn(sleep(Duration::from_secs(60)));
+        let started = tokio::time::Instant::now();
+        close_connections(closing_tx, connections, Duration::from_millis(50)).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn bind_unix_only_replaces_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sovereign.sock");
+        drop(bind_unix(&socket).unwrap());
+        // The socket file outlives its listener, as after a crash
+        assert!(socket.exists());
+        bind_unix(&socket).unwrap();
+
+        let file = dir.path().join("config.yaml");
+        std::fs::write(&file, "keep").unwrap();
+        let err = bind_unix(&file).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,362 @@
+use crate::envoy_types::DiscoveryRequest;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::{de, Deserialize};
+use serde_json::Value as JsonValue;
+use std::str::FromStr;
+use tracing::warn;
+
+/// An attribute of the requesting node that instances can be matched against
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum NodeKey {
+    #[default]
+    Cluster,
+    Id,
+    Region,
+    Zone,
+    SubZone,
+    EnvoyVersion,
+    /// A dotted path into `node.metadata`
+    Metadata(Vec<String>),
+}
+
+impl FromStr for NodeKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cluster" => Ok(NodeKey::Cluster),
+            "id" => Ok(NodeKey::Id),
+            "locality.region" => Ok(NodeKey::Region),
+            "locality.zone" => Ok(NodeKey::Zone),
+            "locality.sub_zone" => Ok(NodeKey::SubZone),
+            "envoy_version" => Ok(NodeKey::EnvoyVersion),
+            _ => match s.strip_prefix("metadata.") {
+                Some(path) => Ok(NodeKey::Metadata(
+                    path.split('.').map(str::to_string).collect(),
+                )),
+                None => Err(format!("Unknown node key: {s}")),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        NodeKey::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for NodeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeKey::Cluster => f.write_str("cluster"),
+            NodeKey::Id => f.write_str("id"),
+            NodeKey::Region => f.write_str("locality.region"),
+            NodeKey::Zone => f.write_str("locality.zone"),
+            NodeKey::SubZone => f.write_str("locality.sub_zone"),
+            NodeKey::EnvoyVersion => f.write_str("envoy_version"),
+            NodeKey::Metadata(path) => write!(f, "metadata.{}", path.join(".")),
+        }
+    }
+}
+
+impl NodeKey {
+    pub fn value(&self, request: &DiscoveryRequest) -> Option<String> {
+        match self {
+            NodeKey::Cluster => Some(request.cluster().to_string()),
+            NodeKey::Id => request.node_id().map(str::to_string),
+            NodeKey::Region => request.locality()?.region.clone(),
+            NodeKey::Zone => request.locality()?.zone.clone(),
+            NodeKey::SubZone => request.locality()?.sub_zone.clone(),
+            NodeKey::EnvoyVersion => request.envoy_version().ok().map(|v| v.to_string()),
+            NodeKey::Metadata(path) => {
+                let (first, rest) = path.split_first()?;
+                let value = rest
+                    .iter()
+                    .try_fold(request.metadata().get(first)?, |v, segment| v.get(segment))?;
+                match value {
+                    JsonValue::String(s) => Some(s.clone()),
+                    JsonValue::Null => None,
+                    other => Some(other.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// How the values held by an instance are compared to the node
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternKind {
+    #[default]
+    Exact,
+    Glob,
+    Regex,
+}
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    Exact(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Target {
+    fn new(kind: PatternKind, value: &str) -> anyhow::Result<Self> {
+        Ok(match kind {
+            PatternKind::Exact => Target::Exact(value.to_string()),
+            PatternKind::Glob => Target::Glob(Glob::new(value)?.compile_matcher()),
+            PatternKind::Regex => Target::Regex(Regex::new(&format!("^(?:{value})$"))?),
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Target::Exact(s) => s == value,
+            Target::Glob(glob) => glob.is_match(value),
+            Target::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// A glob pattern read from config
+#[derive(Debug, Clone)]
+pub struct GlobPattern(GlobMatcher);
+
+impl<'de> Deserialize<'de> for GlobPattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let glob = Glob::new(&s).map_err(de::Error::custom)?;
+        Ok(GlobPattern(glob.compile_matcher()))
+    }
+}
+
+impl GlobPattern {
+    pub fn matches(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+/// Compares the values of an instance key against an attribute of the node
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatchRule {
+    pub source_key: String,
+    #[serde(default)]
+    pub node_key: NodeKey,
+    #[serde(default)]
+    pub pattern: PatternKind,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum NodeMatching {
+    All { all: Vec<NodeMatching> },
+    Any { any: Vec<NodeMatching> },
+    Rule(MatchRule),
+}
+
+/// The compiled values of an instance, one list per rule of the node matching config
+#[derive(Debug, Clone)]
+pub struct Targets(Vec<Vec<Target>>);
+
+impl NodeMatching {
+    /// Every rule in the tree, in the order their values are stored in `Targets`
+    pub fn rules(&self) -> Vec<&MatchRule> {
+        match self {
+            NodeMatching::All { all: children } | NodeMatching::Any { any: children } => {
+                children.iter().flat_map(|m| m.rules()).collect()
+            }
+            NodeMatching::Rule(rule) => vec![rule],
+        }
+    }
+
+    /// The node attribute each rule compares against
+    pub fn node_values(&self, request: &DiscoveryRequest) -> Vec<Option<String>> {
+        self.rules()
+            .into_iter()
+            .map(|rule| rule.node_key.value(request))
+            .collect()
+    }
+
+    /// The raw values an instance holds for each rule, or None if it holds none at all
+    pub fn instance_values(&self, instance: &JsonValue) -> Option<Vec<Vec<String>>> {
+        let values: Vec<Vec<String>> = self
+            .rules()
+            .into_iter()
+            .map(|rule| {
+                let mut values: Vec<String> = match instance.get(&rule.source_key) {
+                    // A list of string values is supported
+                    Some(JsonValue::Array(array)) => array
+                        .iter()
+                        .filter_map(|v| v.as_str().map(str::to_string))
+                        .collect(),
+                    // or a singular string
+                    Some(JsonValue::String(value)) => vec![value.to_string()],
+                    _ => vec![],
+                };
+                values.sort();
+                values.dedup();
+                values
+            })
+            .collect();
+        if values.iter().all(Vec::is_empty) {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    pub fn compile(&self, values: &[Vec<String>]) -> Targets {
+        let targets = self
+            .rules()
+            .into_iter()
+            .zip(values)
+            .map(|(rule, values)| {
+                values
+                    .iter()
+                    .filter_map(|value| match Target::new(rule.pattern, value) {
+                        Ok(target) => Some(target),
+                        Err(e) => {
+                            warn!(source_key = %rule.source_key, value = %value, "Invalid pattern: {e}");
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Targets(targets)
+    }
+
+    /// Whether every instance can be keyed by exact node values, i.e. the rules
+    /// are exact comparisons that must all hold
+    pub fn is_indexable(&self) -> bool {
+        match self {
+            NodeMatching::All { all } => all.iter().all(|m| m.is_indexable()),
+            NodeMatching::Any { .. } => false,
+            NodeMatching::Rule(rule) => matches!(rule.pattern, PatternKind::Exact),
+        }
+    }
+
+    pub fn matches(&self, targets: &Targets, node: &[Option<String>]) -> bool {
+        self.evaluate(targets, node, 0)
+    }
+
+    fn rule_count(&self) -> usize {
+        match self {
+            NodeMatching::All { all: children } | NodeMatching::Any { any: children } => {
+                children.iter().map(|m| m.rule_count()).sum()
+            }
+            NodeMatching::Rule(_) => 1,
+        }
+    }
+
+    /// Pairs each child with the index of its first rule
+    fn offsets(
+        children: &[NodeMatching],
+        offset: usize,
+    ) -> impl Iterator<Item = (&NodeMatching, usize)> {
+        children.iter().scan(offset, |next, m| {
+            let current = *next;
+            *next += m.rule_count();
+            Some((m, current))
+        })
+    }
+
+    fn evaluate(&self, targets: &Targets, node: &[Option<String>], offset: usize) -> bool {
+        match self {
+            NodeMatching::All { all } => {
+                Self::offsets(all, offset).all(|(m, o)| m.evaluate(targets, node, o))
+            }
+            NodeMatching::Any { any } => {
+                Self::offsets(any, offset).any(|(m, o)| m.evaluate(targets, node, o))
+            }
+            NodeMatching::Rule(_) => match (node.get(offset), targets.0.get(offset)) {
+                (Some(Some(value)), Some(targets)) => targets.iter().any(|t| t.matches(value)),
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matching(config: JsonValue) -> NodeMatching {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn request(cluster: &str, id: &str, team: &str) -> DiscoveryRequest {
+        DiscoveryRequest::new(cluster.to_string(), "1.25.0".to_string(), None)
+            .with_node_id(id.to_string())
+            .with_metadata([("team".to_string(), json!({"name": team}))].into())
+    }
+
+    #[test]
+    fn node_keys_read_the_request() {
+        let request = request("front", "front-1", "web");
+        let key = |s: &str| NodeKey::from_str(s).unwrap().value(&request);
+        assert_eq!(key("cluster").as_deref(), Some("front"));
+        assert_eq!(key("id").as_deref(), Some("front-1"));
+        assert_eq!(key("metadata.team.name").as_deref(), Some("web"));
+        assert_eq!(key("metadata.team.missing"), None);
+        assert_eq!(key("locality.zone"), None);
+        assert_eq!(key("envoy_version").as_deref(), Some("1.25.0"));
+        assert!(NodeKey::from_str("nonsense").is_err());
+    }
+
+    #[test]
+    fn instance_values_are_sorted_per_rule() {
+        let matching = matching(json!({"all": [
+            {"source_key": "clusters"},
+            {"source_key": "team", "node_key": "metadata.team.name"},
+        ]}));
+        assert_eq!(
+            matching.instance_values(&json!({"clusters": ["b", "a", "b"], "team": "web"})),
+            Some(vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["web".to_string()]
+            ])
+        );
+        assert_eq!(matching.instance_values(&json!({"name": "x"})), None);
+    }
+
+    #[test]
+    fn nested_rules_read_their_own_offsets() {
+        // Rules are numbered depth first: cluster is 0, id is 1 and team is 2
+        let matching = matching(json!({"all": [
+            {"source_key": "clusters"},
+            {"any": [
+                {"source_key": "ids", "node_key": "id", "pattern": "glob"},
+                {"source_key": "team", "node_key": "metadata.team.name"},
+            ]},
+        ]}));
+        assert!(!matching.is_indexable());
+        let instance = json!({"clusters": ["front"], "ids": ["front-*"], "team": "web"});
+        let targets = matching.compile(&matching.instance_values(&instance).unwrap());
+        let matches =
+            |request: &DiscoveryRequest| matching.matches(&targets, &matching.node_values(request));
+        assert!(matches(&request("front", "front-1", "other")));
+        assert!(matches(&request("front", "back-1", "web")));
+        assert!(!matches(&request("front", "back-1", "other")));
+        assert!(!matches(&request("back", "front-1", "web")));
+    }
+
+    #[test]
+    fn exact_rules_under_all_are_indexable() {
+        assert!(
+            matching(json!({"all": [{"source_key": "a"}, {"source_key": "b"}]})).is_indexable()
+        );
+        assert!(!matching(json!({"any": [{"source_key": "a"}]})).is_indexable());
+        assert!(!matching(json!({"source_key": "a", "pattern": "regex"})).is_indexable());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::context::DataSource;
+use crate::envoy_types::DiscoveryRequest;
+use crate::matching::GlobPattern;
+use crate::sources::block_on;
+use reqwest::Client;
+use serde::{de, Deserialize};
+use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
+
+const SECRET_TYPE: &str = "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.Secret";
+const REDACTED: &str = "[redacted]";
+const LOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A value that is never printed, so that it can't end up in logs or error messages
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// A field of a Vault KV secret, read from `<address>/v1/<path>`
+    Vault {
+        address: String,
+        path: String,
+        #[serde(default = "default_vault_field")]
+        field: String,
+        /// Falls back to `VAULT_TOKEN`
+        token: Option<Secret>,
+    },
+    #[serde(untagged)]
+    Data(DataSource),
+}
+
+fn default_vault_field() -> String {
+    "value".to_string()
+}
+
+impl SecretSource {
+    fn load(&self) -> anyhow::Result<Secret> {
+        let data = match self {
+            SecretSource::Vault {
+                address,
+                path,
+                field,
+                token,
+            } => {
+                let url = format!("{}/v1/{}", address.trim_end_matches('/'), path);
+                let token = match token {
+                    Some(token) => token.expose().to_string(),
+                    None => std::env::var("VAULT_TOKEN")
+                        .map_err(|_| anyhow::anyhow!("No Vault token for {path}"))?,
+                };
+                let future = async move {
+                    let response = Client::new()
+                        .get(url)
+                        .header("X-Vault-Token", token)
+                        .timeout(LOAD_TIMEOUT)
+                        .send()
+                        .await?
+                        .error_for_status()?;
+                    response.json::<JsonValue>().await
+                };
+                let body = block_on(future)?.map_err(|e| e.without_url())?;
+                // KV version 2 nests the secret one level deeper than version 1
+                let value = body["data"]["data"]
+                    .get(field)
+                    .or_else(|| body["data"].get(field))
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| anyhow::anyhow!("Vault secret {path} has no field {field}"))?;
+                value.to_string()
+            }
+            SecretSource::Data(source) => String::from_utf8(source.fetch(LOAD_TIMEOUT)?)?,
+        };
+        Ok(Secret(data))
+    }
+}
+
+/// The kinds of SDS secret, with where each of their values come from
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretKind {
+    TlsCertificate {
+        certificate_chain: SecretSource,
+        private_key: SecretSource,
+    },
+    ValidationContext {
+        trusted_ca: SecretSource,
+    },
+    GenericSecret {
+        secret: SecretSource,
+    },
+}
+
+impl SecretKind {
+    /// The field of the Envoy secret that holds the values, and each value's sources
+    fn fields(&self) -> (&'static str, Vec<(&'static str, &SecretSource)>) {
+        match self {
+            SecretKind::TlsCertificate {
+                certificate_chain,
+                private_key,
+            } => (
+                "tls_certificate",
+                vec![
+                    ("certificate_chain", certificate_chain),
+                    ("private_key", private_key),
+                ],
+            ),
+            SecretKind::ValidationContext { trusted_ca } => {
+                ("validation_context", vec![("trusted_ca", trusted_ca)])
+            }
+            SecretKind::GenericSecret { secret } => ("generic_secret", vec![("secret", secret)]),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SecretItem {
+    pub name: String,
+    /// The node clusters this secret may be sent to
+    pub clusters: Vec<GlobPattern>,
+    #[serde(flatten)]
+    pub kind: SecretKind,
+}
+
+struct LoadedSecret {
+    name: String,
+    clusters: Vec<GlobPattern>,
+    section: &'static str,
+    values: Vec<(&'static str, Secret)>,
+}
+
+impl LoadedSecret {
+    fn resource(&self, redact: bool) -> JsonValue {
+        let values: serde_json::Map<String, JsonValue> = self
+            .values
+            .iter()
+            .map(|(field, secret)| {
+                let value = if redact { REDACTED } else { secret.expose() };
+                (field.to_string(), json!({ "inline_string": value }))
+            })
+            .collect();
+        json!({
+            "@type": SECRET_TYPE,
+            "name": self.name,
+            self.section: values,
+        })
+    }
+}
+
+/// Every configured secret, loaded
+pub struct SecretStore {
+    secrets: Vec<LoadedSecret>,
+}
+
+impl SecretStore {
+    /// The secrets the node may receive, limited to the names it asked for.
+    /// The cluster is the one the client claims, so the request must be authorized first.
+    pub fn resources(&self, request: &DiscoveryRequest, redact: bool) -> JsonValue {
+        let names = request.resource_names();
+        self.secrets
+            .iter()
+            .filter(|s| s.clusters.iter().any(|c| c.matches(request.cluster())))
+            .filter(|s| names.is_empty() || names.contains(&s.name))
+            .map(|s| s.resource(redact))
+            .collect()
+    }
+}
+
+pub fn poll_secrets(items: &[SecretItem]) -> anyhow::Result<SecretStore> {
+    let mut secrets = vec![];
+    for item in items.iter() {
+        let (section, sources) = item.kind.fields();
+        let mut values = vec![];
+        for (field, source) in sources {
+            let secret = source
+                .load()
+                .map_err(|e| anyhow::anyhow!("Could not load secret {}: {e}", item.name))?;
+            values.push((field, secret));
+        }
+        secrets.push(LoadedSecret {
+            name: item.name.clone(),
+            clusters: item.clusters.clone(),
+            section,
+            values,
+        });
+    }
+    Ok(SecretStore { secrets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use axum::http::HeaderMap;
+
+    fn store(items: JsonValue) -> SecretStore {
+        poll_secrets(&serde_json::from_value::<Vec<SecretItem>>(items).unwrap()).unwrap()
+    }
+
+    fn generic(name: &str, clusters: &[&str], value: &str) -> JsonValue {
+        json!({
+            "name": name,
+            "clusters": clusters,
+            "generic_secret": {"secret": {"env": {"variable": "SOVEREIGN_TEST_SECRET_UNSET", "default": value}}},
+        })
+    }
+
+    fn names(resources: JsonValue) -> Vec<String> {
+        resources
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn secrets_are_only_sent_to_clusters_the_credential_allows() {
+        let store = store(json!([
+            generic("edge-cert", &["edge-*"], "a"),
+            generic("db-cert", &["db-*"], "b"),
+            generic("shared", &["*"], "c"),
+        ]));
+        let auth: AuthConfig = serde_json::from_value(json!({
+            "credentials": [{"type": "bearer", "token": "edge-token", "clusters": ["edge-*"]}],
+        }))
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer edge-token".parse().unwrap());
+        let request = |cluster: &str| DiscoveryRequest::new(cluster.into(), "1.25.0".into(), None);
+
+        let edge = request("edge-1");
+        assert!(auth.authorize(&headers, None, &edge).is_ok());
+        assert_eq!(
+            names(store.resources(&edge, false)),
+            ["edge-cert", "shared"]
+        );
+        // Claiming another cluster fails before any secret is looked at
+        assert!(auth.authorize(&headers, None, &request("db-1")).is_err());
+
+        let named = DiscoveryRequest::new(
+            "edge-1".into(),
+            "1.25.0".into(),
+            Some(vec!["shared".into()]),
+        );
+        assert_eq!(names(store.resources(&named, false)), ["shared"]);
+    }
+
+    #[test]
+    fn secret_values_are_redacted() {
+        let secret = Secret("hunter2".into());
+        assert_eq!(format!("{secret:?} {secret}"), "[redacted] [redacted]");
+
+        let store = store(json!([generic("cert", &["*"], "hunter2")]));
+        let request = DiscoveryRequest::new("edge".into(), "1.25.0".into(), None);
+        assert!(!store
+            .resources(&request, true)
+            .to_string()
+            .contains("hunter2"));
+        assert!(store
+            .resources(&request, false)
+            .to_string()
+            .contains("hunter2"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn load_errors_leave_out_the_token() {
+        let source: SecretSource = serde_json::from_value(json!({
+            "vault": {"address": "http://127.0.0.1:1", "path": "secret/cert", "token": "hunter2"},
+        }))
+        .unwrap();
+        let err = source.load().err().unwrap();
+        assert!(!format!("{err:?}").contains("hunter2"), "{err:?}");
+    }
+}
@@ -1,34 +1,229 @@
+use crate::context::{DeserializeAs, Parsed};
+use crate::envoy_types::DiscoveryRequest;
+use crate::git::resolve;
+use crate::health::HealthMap;
+use crate::matching::{NodeMatching, Targets};
+use dashmap::DashMap;
+use minijinja::Value as JinjaValue;
 use pyo3::prelude::*;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
+use std::future::Future;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+pub mod consul;
+#[cfg(feature = "dns")]
+pub mod dns;
+pub mod exec;
+pub mod transform;
+
+use transform::Transform;
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type", content = "config", rename_all = "snake_case")]
 pub enum Source {
-    Inline { data: JsonValue },
-    PythonInline { code: String },
-    PythonScript { path: PathBuf },
-    Http { url: String },
-    File { path: PathBuf },
+    Inline {
+        data: JsonValue,
+    },
+    PythonInline {
+        code: String,
+    },
+    PythonScript {
+        path: PathBuf,
+    },
+    Http {
+        url: String,
+    },
+    File {
+        path: PathBuf,
+    },
+    Consul(consul::Consul),
+    #[cfg(feature = "dns")]
+    Dns(dns::Dns),
+    Exec {
+        #[serde(flatten)]
+        exec: exec::Exec,
+        #[serde(default)]
+        deserialize_as: DeserializeAs,
+    },
 }
 
-/// Tag that indicates which cluster a bundle of instances is intended for
-#[derive(Clone, Serialize)]
-pub enum SourceDest {
-    Any,
-    Match(String),
+/// How discovered services are presented to templates, in the same shape as other sources
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ServiceInstance {
+    /// Clusters of the nodes the service is served to, see `node_matching`
+    #[serde(default)]
+    service_clusters: Vec<String>,
+    /// Defaults to the service name
+    resource_name: Option<String>,
+}
+
+impl ServiceInstance {
+    /// Instances without the clusters `node_matching` reads would never be served
+    pub fn check(&self, matching: &NodeMatching) -> Result<(), String> {
+        let matched = matching
+            .rules()
+            .iter()
+            .any(|rule| rule.source_key == "service_clusters");
+        if matched && self.service_clusters.is_empty() {
+            return Err("service_clusters is needed to match instances to nodes".into());
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, service_name: &str, upstreams: Vec<JsonValue>) -> JsonValue {
+        json!({
+            "name": service_name,
+            "service_clusters": self.service_clusters,
+            "parameters": {
+                "service_name": service_name,
+                "resource_name": self.resource_name.as_deref().unwrap_or(service_name),
+                "upstream_address": upstreams,
+            },
+        })
+    }
+}
+
+/// A source along with the transforms applied to its instances
+#[derive(Debug, Deserialize, Clone)]
+pub struct SourceItem {
+    #[serde(flatten)]
+    pub source: Source,
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+}
+
+/// A snapshot of every polled instance, indexed by the nodes they are intended for.
+///
+/// Instances are converted to template values once, when the snapshot is built,
+/// so that looking up a bucket only clones reference-counted values.
+pub struct InstanceIndex {
+    matching: Option<NodeMatching>,
+    /// Instances that are served to every node, ahead of those matched to it.
+    /// With node matching, these are only the unmatched instances opted in by `serve_unmatched`.
+    any: JinjaValue,
+    /// Instances keyed by the node values they match
+    buckets: HashMap<Vec<String>, JinjaValue>,
+    /// Groups of instances whose targets can't be keyed up front
+    unindexed: Vec<(Targets, Vec<JinjaValue>)>,
+    /// Unindexed lookups, memoized per distinct set of node values up to `MAX_RESOLVED`
+    resolved: DashMap<Vec<Option<String>>, JinjaValue>,
+}
+
+/// Node values come from clients, so only this many lookups are memoized per snapshot
+const MAX_RESOLVED: usize = 10_000;
+
+impl Default for InstanceIndex {
+    /// No instances, as served before sources have been polled
+    fn default() -> Self {
+        Self::new(vec![])
+    }
 }
 
-/// A pre-coalesced group of instances, for one particular cluster
-#[derive(Clone, Serialize)]
-pub struct InstancesPackage {
-    pub dest: SourceDest,
-    pub instances: JsonValue,
+impl InstanceIndex {
+    fn new(any: Vec<JsonValue>) -> Self {
+        Self {
+            matching: None,
+            any: JinjaValue::from_serializable(&any),
+            buckets: HashMap::new(),
+            unindexed: vec![],
+            resolved: DashMap::new(),
+        }
+    }
+
+    fn bucketed(
+        matching: &NodeMatching,
+        any: Vec<JsonValue>,
+        groups: Vec<(Vec<Vec<String>>, Vec<JsonValue>)>,
+    ) -> Self {
+        let mut index = Self::new(any);
+        index.matching = Some(matching.clone());
+        let any: Vec<JinjaValue> = index.any.try_iter().into_iter().flatten().collect();
+        if matching.is_indexable() {
+            let mut buckets: HashMap<Vec<String>, Vec<JinjaValue>> = HashMap::new();
+            for (values, instances) in groups {
+                let instances: Vec<JinjaValue> = instances
+                    .iter()
+                    .map(JinjaValue::from_serializable)
+                    .collect();
+                // An instance belongs to every combination of the values it holds per rule
+                let keys = values.iter().fold(vec![vec![]], |keys, rule_values| {
+                    keys.iter()
+                        .flat_map(|key: &Vec<String>| {
+                            rule_values.iter().map(move |value| {
+                                let mut key = key.clone();
+                                key.push(value.clone());
+                                key
+                            })
+                        })
+                        .collect()
+                });
+                for key in keys {
+                    buckets
+                        .entry(key)
+                        .or_insert_with(|| any.clone())
+                        .extend(instances.iter().cloned());
+                }
+            }
+            index.buckets = buckets
+                .into_iter()
+                .map(|(key, instances)| (key, JinjaValue::from(instances)))
+                .collect();
+        } else {
+            index.unindexed = groups
+                .into_iter()
+                .map(|(values, instances)| {
+                    (
+                        matching.compile(&values),
+                        instances
+                            .iter()
+                            .map(JinjaValue::from_serializable)
+                            .collect(),
+                    )
+                })
+                .collect();
+        }
+        index
+    }
+
+    /// The instances intended for the node that made this request
+    pub fn instances(&self, request: &DiscoveryRequest) -> JinjaValue {
+        let Some(matching) = &self.matching else {
+            return self.any.clone();
+        };
+        let node = matching.node_values(request);
+        if matching.is_indexable() {
+            // A node lacking any of the matched attributes can't have a bucket
+            let key: Option<Vec<String>> = node.into_iter().collect();
+            return key
+                .and_then(|key| self.buckets.get(&key).cloned())
+                .unwrap_or_else(|| self.any.clone());
+        }
+        if let Some(resolved) = self.resolved.get(&node) {
+            return resolved.clone();
+        }
+        let matched: Vec<JinjaValue> = self
+            .any
+            .try_iter()
+            .into_iter()
+            .flatten()
+            .chain(
+                self.unindexed
+                    .iter()
+                    .filter(|(targets, _)| matching.matches(targets, &node))
+                    .flat_map(|(_, instances)| instances.iter().cloned()),
+            )
+            .collect();
+        let matched = JinjaValue::from(matched);
+        if self.resolved.len() < MAX_RESOLVED {
+            self.resolved.insert(node, matched.clone());
+        }
+        matched
+    }
 }
 
 fn call_python_code(code: &str) -> anyhow::Result<String> {
@@ -38,8 +233,21 @@ fn call_python_code(code: &str) -> anyhow::Result<String> {
     })
 }
 
-fn read_file(path: &PathBuf) -> anyhow::Result<String> {
-    let file = std::fs::File::open(path)?;
+/// Runs a future to completion from the synchronous polling code
+pub(crate) fn block_on<F>(future: F) -> Result<F::Output, tokio::task::JoinError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let handle = tokio::task::spawn(future);
+    tokio::task::block_in_place(|| {
+        let runtime = tokio::runtime::Handle::current();
+        runtime.block_on(handle)
+    })
+}
+
+fn read_file(path: &Path) -> anyhow::Result<String> {
+    let file = std::fs::File::open(resolve(path))?;
     let mut reader = BufReader::new(file);
     let mut content = String::new();
     reader.read_to_string(&mut content)?;
@@ -47,6 +255,16 @@ fn read_file(path: &PathBuf) -> anyhow::Result<String> {
 }
 
 impl Source {
+    /// How a discovery source presents its services, for those that discover them
+    pub fn service_instance(&self) -> Option<&ServiceInstance> {
+        match self {
+            Source::Consul(consul) => Some(consul.instance()),
+            #[cfg(feature = "dns")]
+            Source::Dns(dns) => Some(dns.instance()),
+            _ => None,
+        }
+    }
+
     pub fn get(&self) -> anyhow::Result<String> {
         match self {
             Source::Inline { data } => Ok(data.to_string()),
@@ -58,80 +276,184 @@ impl Source {
                     let client = Client::new();
                     client.get(u).send().await.unwrap().text().await.unwrap()
                 };
-                let handle = tokio::task::spawn(future);
-                let result = tokio::task::block_in_place(|| {
-                    let runtime = tokio::runtime::Handle::current();
-                    runtime.block_on(handle)
-                });
-                Ok(result.unwrap())
+                Ok(block_on(future)?)
             }
             Source::File { path } => read_file(path),
+            Source::Consul(consul) => consul.get(),
+            #[cfg(feature = "dns")]
+            Source::Dns(dns) => dns.get(),
+            Source::Exec {
+                exec,
+                deserialize_as,
+            } => match deserialize_as.parse(exec.run()?)? {
+                Parsed::Structured(data) => Ok(data.to_string()),
+                Parsed::Text(text) => Ok(text),
+            },
         }
     }
 }
 
-pub fn poll_sources(sources: &[Source]) -> anyhow::Result<Vec<InstancesPackage>> {
-    let mut source_data = json! {[]};
-    let borrow = source_data.as_array_mut().unwrap();
-    for source in sources.iter() {
-        let data = source.get()?;
-        let json_value = serde_json::from_str::<JsonValue>(&data)?;
-        let instances = json_value.as_array().unwrap();
-        borrow.extend(instances.clone());
+impl SourceItem {
+    pub fn instances(&self) -> anyhow::Result<Vec<JsonValue>> {
+        let data = self.source.get()?;
+        let mut instances = match serde_json::from_str::<JsonValue>(&data)? {
+            JsonValue::Array(instances) => instances,
+            _ => anyhow::bail!("Source did not return a list of instances"),
+        };
+        for transform in self.transforms.iter() {
+            instances = transform.apply(instances)?;
+        }
+        Ok(instances)
     }
-    Ok(vec![InstancesPackage {
-        dest: SourceDest::Any,
-        instances: source_data,
-    }])
 }
 
+/// Polls every source, recording how each went as `items[<position>]` when given a health map.
+/// Fails with the first error, after every source has been polled.
+fn poll_items(
+    sources: &[SourceItem],
+    health: Option<&HealthMap>,
+) -> anyhow::Result<Vec<Vec<JsonValue>>> {
+    let mut polled = vec![];
+    let mut failure = None;
+    for (i, source) in sources.iter().enumerate() {
+        let result = source.instances();
+        if let Some(health) = health {
+            health.record(&format!("items[{i}]"), &result);
+        }
+        match result {
+            Ok(instances) => polled.push(instances),
+            Err(e) => failure = failure.or(Some(e)),
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(polled),
+    }
+}
+
+pub fn poll_sources(sources: &[SourceItem]) -> anyhow::Result<InstanceIndex> {
+    Ok(index(poll_items(sources, None)?, None, false))
+}
+
+/// Buckets instances by the node values they match. Those holding none of the
+/// match keys are dropped, unless `serve_unmatched` serves them to every node.
 pub fn poll_sources_into_buckets(
-    sources: &[Source],
-    source_match_key: &str,
-) -> anyhow::Result<Vec<InstancesPackage>> {
-    let mut ret = vec![];
-    let mut buckets = HashMap::new();
-    for source in sources.iter() {
-        let data = source.get()?;
-        let json_value = serde_json::from_str::<JsonValue>(&data)?;
-        let instances = json_value.as_array().unwrap();
+    sources: &[SourceItem],
+    matching: &NodeMatching,
+    serve_unmatched: bool,
+) -> anyhow::Result<InstanceIndex> {
+    Ok(index(
+        poll_items(sources, None)?,
+        Some(matching),
+        serve_unmatched,
+    ))
+}
+
+/// Polls sources like `poll_sources` or `poll_sources_into_buckets`, recording each one's health
+pub fn poll_sources_with_health(
+    sources: &[SourceItem],
+    matching: Option<&NodeMatching>,
+    serve_unmatched: bool,
+    health: &HealthMap,
+) -> anyhow::Result<InstanceIndex> {
+    Ok(index(
+        poll_items(sources, Some(health))?,
+        matching,
+        serve_unmatched,
+    ))
+}
 
+fn index(
+    polled: Vec<Vec<JsonValue>>,
+    matching: Option<&NodeMatching>,
+    serve_unmatched: bool,
+) -> InstanceIndex {
+    let Some(matching) = matching else {
+        return InstanceIndex::new(polled.into_iter().flatten().collect());
+    };
+    // Instances are grouped by the values they hold for each match dimension,
+    // keeping the order of the sources so that rendered output is stable
+    let mut any = vec![];
+    let mut groups: Vec<(Vec<Vec<String>>, Vec<JsonValue>)> = vec![];
+    let mut positions: HashMap<Vec<Vec<String>>, usize> = HashMap::new();
+    for instances in polled {
         for instance in instances {
-            match instance.get(source_match_key) {
-                // A list of string values is supported
-                Some(JsonValue::Array(array)) => {
-                    // Add a copy of the instance to every bucket
-                    for bucket in array {
-                        if let JsonValue::String(matched_key) = bucket {
-                            buckets
-                                .entry(matched_key.to_string())
-                                .or_insert(json! {[]})
-                                .as_array_mut()
-                                .unwrap()
-                                .push(instance.clone());
-                        } else {
-                            continue;
-                        }
-                    }
+            let Some(values) = matching.instance_values(&instance) else {
+                if serve_unmatched {
+                    any.push(instance);
                 }
-                // or a singular string
-                Some(JsonValue::String(key)) => {
-                    buckets
-                        .entry(key.to_string())
-                        .or_insert(json! {[]})
-                        .as_array_mut()
-                        .unwrap()
-                        .push(instance.clone());
-                }
-                _ => continue,
-            }
+                continue;
+            };
+            let position = *positions.entry(values.clone()).or_insert_with(|| {
+                groups.push((values, vec![]));
+                groups.len() - 1
+            });
+            groups[position].1.push(instance);
         }
     }
-    for (bucket, instances) in buckets.into_iter() {
-        ret.push(InstancesPackage {
-            dest: SourceDest::Match(bucket),
-            instances,
-        });
+    InstanceIndex::bucketed(matching, any, groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(index: &InstanceIndex, cluster: &str) -> Vec<String> {
+        let request = DiscoveryRequest::new(cluster.to_string(), "1.25.0".to_string(), None);
+        index
+            .instances(&request)
+            .try_iter()
+            .unwrap()
+            .map(|instance| instance.get_attr("name").unwrap().to_string())
+            .collect()
+    }
+
+    fn polled() -> Vec<Vec<JsonValue>> {
+        vec![
+            vec![
+                json!({"name": "a", "service_clusters": ["x", "y"]}),
+                json!({"name": "shared"}),
+            ],
+            vec![json!({"name": "b", "service_clusters": "y"})],
+        ]
+    }
+
+    #[test]
+    fn unmatched_instances_are_dropped() {
+        let matching = serde_json::from_value(json!({"source_key": "service_clusters"})).unwrap();
+        let index = index(polled(), Some(&matching), false);
+        assert_eq!(names(&index, "x"), ["a"]);
+        assert_eq!(names(&index, "y"), ["a", "b"]);
+        assert!(names(&index, "z").is_empty());
+    }
+
+    #[test]
+    fn unmatched_instances_can_be_served_to_every_node() {
+        let matching = serde_json::from_value(json!({"source_key": "service_clusters"})).unwrap();
+        let index = index(polled(), Some(&matching), true);
+        assert_eq!(names(&index, "x"), ["shared", "a"]);
+        assert_eq!(names(&index, "y"), ["shared", "a", "b"]);
+        assert_eq!(names(&index, "z"), ["shared"]);
+    }
+
+    #[test]
+    fn unindexed_lookups_drop_unmatched_unless_served() {
+        let matching =
+            serde_json::from_value(json!({"source_key": "service_clusters", "pattern": "glob"}))
+                .unwrap();
+        let mut polled = polled();
+        polled.push(vec![json!({"name": "c", "service_clusters": "y*"})]);
+        let index = index(polled.clone(), Some(&matching), true);
+        assert_eq!(names(&index, "y"), ["shared", "a", "b", "c"]);
+        assert_eq!(names(&index, "yz"), ["shared", "c"]);
+        assert_eq!(index.resolved.len(), 2);
+        let index = super::index(polled, Some(&matching), false);
+        assert_eq!(names(&index, "yz"), ["c"]);
+    }
+
+    #[test]
+    fn without_matching_every_instance_is_served() {
+        let index = index(polled(), None, false);
+        assert_eq!(names(&index, "z"), ["a", "shared", "b"]);
     }
-    Ok(ret)
 }
@@ -0,0 +1,271 @@
+use super::{block_on, ServiceInstance};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::time::{sleep, Duration};
+use tracing::warn;
+use url::Url;
+
+/// Instances of a single service, built from Consul's health API
+#[derive(Debug, Deserialize, Clone)]
+pub struct Consul {
+    #[serde(flatten)]
+    query: Query,
+    #[serde(skip)]
+    state: Arc<Mutex<BlockingState>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Query {
+    #[serde(default = "default_address")]
+    address: String,
+    service: String,
+    datacenter: Option<String>,
+    tag: Option<String>,
+    token: Option<String>,
+    /// Only include endpoints whose checks are all passing
+    #[serde(default)]
+    passing_only: bool,
+    /// Seconds to hold a blocking query open while waiting for a catalog change.
+    /// Blocking queries run in the background, and polls return their latest result.
+    wait: Option<u64>,
+    #[serde(flatten)]
+    instance: ServiceInstance,
+}
+
+/// The last index returned by Consul, and the instances rendered from it
+#[derive(Debug, Default)]
+struct BlockingState {
+    index: Option<String>,
+    instances: Option<String>,
+    /// Why the latest blocking query failed
+    error: Option<String>,
+    watching: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: ConsulNode,
+    service: ConsulService,
+    checks: Vec<ConsulCheck>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    node: String,
+    address: String,
+    datacenter: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulService {
+    #[serde(rename = "ID")]
+    id: String,
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<JsonValue>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulCheck {
+    #[serde(rename = "CheckID")]
+    check_id: String,
+    status: String,
+}
+
+fn default_address() -> String {
+    "http://127.0.0.1:8500".to_string()
+}
+
+/// Collapses Consul check statuses into an Envoy health status
+fn health_status(checks: &[ConsulCheck]) -> &'static str {
+    let maintenance = checks.iter().any(|c| {
+        c.check_id == "_node_maintenance" || c.check_id.starts_with("_service_maintenance")
+    });
+    if maintenance {
+        "DRAINING"
+    } else if checks.iter().any(|c| c.status == "critical") {
+        "UNHEALTHY"
+    } else if checks.iter().any(|c| c.status == "warning") {
+        "DEGRADED"
+    } else {
+        "HEALTHY"
+    }
+}
+
+impl Query {
+    fn url(&self, index: Option<&str>) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&self.address)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid Consul address: {}", self.address))?
+            .pop_if_empty()
+            .extend(["v1", "health", "service", &self.service]);
+        let mut params = vec![];
+        if let Some(dc) = &self.datacenter {
+            params.push(("dc", dc.clone()));
+        }
+        if let Some(tag) = &self.tag {
+            params.push(("tag", tag.clone()));
+        }
+        if self.passing_only {
+            params.push(("passing", "true".to_string()));
+        }
+        // Without a wait, Consul defaults to holding the query for five minutes
+        if let (Some(index), Some(wait)) = (index, self.wait) {
+            params.push(("index", index.to_string()));
+            params.push(("wait", format!("{wait}s")));
+        }
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        Ok(url)
+    }
+
+    /// Queries the health API, returning the index of the response and its body
+    async fn fetch(&self, index: Option<&str>) -> anyhow::Result<(Option<String>, String)> {
+        let mut request = Client::new().get(self.url(index)?);
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        // Consul adds up to wait/16 to a blocking query, to spread out responses
+        if let (Some(_), Some(wait)) = (index, self.wait) {
+            request = request.timeout(Duration::from_secs(wait + wait / 16 + 10));
+        }
+        let response = request.send().await?.error_for_status()?;
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        Ok((index, response.text().await?))
+    }
+
+    fn instances(&self, entries: Vec<HealthEntry>) -> JsonValue {
+        let upstreams: Vec<JsonValue> = entries
+            .into_iter()
+            .map(|entry| {
+                let address = if entry.service.address.is_empty() {
+                    entry.node.address
+                } else {
+                    entry.service.address
+                };
+                json!({
+                    "address": address,
+                    "port": entry.service.port,
+                    "health_status": health_status(&entry.checks),
+                    "id": entry.service.id,
+                    "node": entry.node.node,
+                    "datacenter": entry.node.datacenter,
+                    "tags": entry.service.tags.unwrap_or_default(),
+                    "meta": entry.service.meta.unwrap_or_else(|| json!({})),
+                })
+            })
+            .collect();
+        json!([self.instance.render(&self.service, upstreams)])
+    }
+
+    /// Renders a response into `state`, unless its index shows nothing changed
+    fn update(
+        &self,
+        state: &Mutex<BlockingState>,
+        index: Option<String>,
+        body: String,
+    ) -> anyhow::Result<String> {
+        let mut state = state.lock().unwrap();
+        if let (Some(_), Some(cached)) = (&index, &state.instances) {
+            if index == state.index {
+                return Ok(cached.clone());
+            }
+        }
+        let entries: Vec<HealthEntry> = serde_json::from_str(&body)?;
+        let instances = self.instances(entries).to_string();
+        state.index = index;
+        state.instances = Some(instances.clone());
+        Ok(instances)
+    }
+
+    /// Runs blocking queries until the source is dropped, keeping `state` up to date
+    async fn watch(self, state: Weak<Mutex<BlockingState>>) {
+        loop {
+            let Some(index) = state.upgrade().map(|s| s.lock().unwrap().index.clone()) else {
+                return;
+            };
+            let result = self.fetch(index.as_deref()).await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let result = result.and_then(|(index, body)| self.update(&state, index, body));
+            let error = result.err().map(|e| e.to_string());
+            state.lock().unwrap().error = error.clone();
+            drop(state);
+            if let Some(e) = error {
+                warn!(service = %self.service, "Consul blocking query failed: {e}");
+                sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+impl Consul {
+    pub fn instance(&self) -> &ServiceInstance {
+        &self.query.instance
+    }
+
+    pub fn get(&self) -> anyhow::Result<String> {
+        {
+            let state = self.state.lock().unwrap();
+            if state.watching {
+                if let Some(error) = &state.error {
+                    anyhow::bail!("{error}");
+                }
+                if let Some(instances) = &state.instances {
+                    return Ok(instances.clone());
+                }
+            }
+        }
+        // Only the background watch makes blocking queries, so that a poll returns immediately
+        let query = self.query.clone();
+        let (index, body) = block_on(async move { query.fetch(None).await })??;
+        let instances = self.query.update(&self.state, index, body)?;
+
+        let mut state = self.state.lock().unwrap();
+        if self.query.wait.is_some() && !state.watching {
+            state.watching = true;
+            tokio::spawn(self.query.clone().watch(Arc::downgrade(&self.state)));
+        }
+        Ok(instances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_encodes_query_values() {
+        let query: Query = serde_json::from_value(json!({
+            "address": "http://consul:8500/",
+            "service": "web/api",
+            "datacenter": "dc1&x=1",
+            "tag": "a b",
+            "wait": 30,
+        }))
+        .unwrap();
+        assert_eq!(
+            query.url(Some("7")).unwrap().as_str(),
+            "http://consul:8500/v1/health/service/web%2Fapi?dc=dc1%26x%3D1&tag=a+b&index=7&wait=30s"
+        );
+        assert_eq!(
+            query.url(None).unwrap().as_str(),
+            "http://consul:8500/v1/health/service/web%2Fapi?dc=dc1%26x%3D1&tag=a+b"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use super::{block_on, ServiceInstance};
+use hickory_resolver::TokioAsyncResolver;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordType {
+    #[default]
+    Srv,
+    A,
+}
+
+/// Instances of a single service, resolved from DNS and refreshed once the records expire
+#[derive(Debug, Deserialize, Clone)]
+pub struct Dns {
+    name: String,
+    #[serde(default)]
+    record_type: RecordType,
+    /// Port for A records, which unlike SRV records do not carry one
+    port: Option<u16>,
+    /// Name of the service in the rendered instance, defaults to the record name
+    service_name: Option<String>,
+    #[serde(flatten)]
+    instance: ServiceInstance,
+    #[serde(skip)]
+    cache: Arc<Mutex<Option<(Instant, String)>>>,
+}
+
+impl Dns {
+    pub fn instance(&self) -> &ServiceInstance {
+        &self.instance
+    }
+
+    async fn resolve(
+        name: String,
+        record_type: RecordType,
+        port: Option<u16>,
+    ) -> anyhow::Result<(Instant, Vec<JsonValue>)> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        let mut upstreams = vec![];
+        let valid_until = match record_type {
+            RecordType::Srv => {
+                let lookup = resolver.srv_lookup(name).await?;
+                let mut valid_until = lookup.as_lookup().valid_until();
+                for srv in lookup.iter() {
+                    let target = srv.target().to_utf8();
+                    let ips = resolver.lookup_ip(srv.target().clone()).await?;
+                    valid_until = valid_until.min(ips.valid_until());
+                    for ip in ips.iter() {
+                        upstreams.push(json!({
+                            "address": ip.to_string(),
+                            "port": srv.port(),
+                            "hostname": target.trim_end_matches('.'),
+                            "priority": srv.priority(),
+                            "weight": srv.weight(),
+                            // DNS has no notion of health, Envoy treats UNKNOWN as routable
+                            "health_status": "UNKNOWN",
+                        }));
+                    }
+                }
+                valid_until
+            }
+            RecordType::A => {
+                let port =
+                    port.ok_or_else(|| anyhow::anyhow!("A record sources require a port"))?;
+                let ips = resolver.lookup_ip(name).await?;
+                for ip in ips.iter() {
+                    upstreams.push(json!({
+                        "address": ip.to_string(),
+                        "port": port,
+                        "health_status": "UNKNOWN",
+                    }));
+                }
+                ips.valid_until()
+            }
+        };
+        Ok((valid_until, upstreams))
+    }
+
+    pub fn get(&self) -> anyhow::Result<String> {
+        if let Some((valid_until, instances)) = &*self.cache.lock().unwrap() {
+            if Instant::now() < *valid_until {
+                return Ok(instances.clone());
+            }
+        }
+        let (valid_until, upstreams) = block_on(Self::resolve(
+            self.name.clone(),
+            self.record_type,
+            self.port,
+        ))??;
+        let service_name = self.service_name.as_ref().unwrap_or(&self.name);
+        let instances = json!([self.instance.render(service_name, upstreams)]).to_string();
+        *self.cache.lock().unwrap() = Some((valid_until, instances.clone()));
+        Ok(instances)
+    }
+}
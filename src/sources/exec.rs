@@ -0,0 +1,184 @@
+use super::block_on;
+use crate::config::deserialize_duration;
+use crate::git::resolve;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+use tracing::warn;
+
+/// A command whose stdout is the data, e.g. a CLI that lists services
+#[derive(Debug, Deserialize, Clone)]
+pub struct Exec {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Added to the environment the server runs with
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(deserialize_with = "deserialize_duration", default = "default_timeout")]
+    timeout: Duration,
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Exec {
+    /// Paths like `scripts/list.sh` are read from the git checkout, bare names from PATH
+    fn program(&self) -> OsString {
+        if self.command.contains('/') {
+            resolve(Path::new(&self.command)).into_os_string()
+        } else {
+            self.command.clone().into()
+        }
+    }
+
+    /// Checks the command can be found, without running it
+    pub fn check(&self) -> Result<(), String> {
+        let program = self.program();
+        let found = if self.command.contains('/') {
+            Path::new(&program).is_file()
+        } else {
+            env::var_os("PATH")
+                .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(&program).is_file()))
+        };
+        match found {
+            true => Ok(()),
+            false => Err(format!("command {} not found", self.command)),
+        }
+    }
+
+    /// Runs the command, failing if it exits non-zero or outlives its timeout
+    pub fn run(&self) -> anyhow::Result<Vec<u8>> {
+        let mut command = Command::new(self.program());
+        command
+            .args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let limit = self.timeout;
+        let (finished, stderr) = block_on(output(command, limit))?
+            .map_err(|e| anyhow::anyhow!("Could not run {}: {e}", self.command))?;
+        let stderr = String::from_utf8_lossy(&stderr);
+        match finished {
+            None => {
+                warn!(command = %self.command, stderr = %stderr.trim(), "Command timed out");
+                anyhow::bail!("{} timed out after {limit:?}", self.command)
+            }
+            Some((status, _)) if !status.success() => {
+                warn!(command = %self.command, stderr = %stderr.trim(), "Command failed");
+                anyhow::bail!("{} exited with {status}", self.command)
+            }
+            Some((_, stdout)) => Ok(stdout),
+        }
+    }
+}
+
+/// How long stderr is read for after a timed out command is killed, since anything it
+/// started may still hold the pipe open
+const DRAIN: Duration = Duration::from_millis(100);
+
+/// Waits for the command and its stdout, killing it after `limit`. Stderr holds whatever
+/// the command wrote, whether or not it finished in time.
+async fn output(
+    mut command: Command,
+    limit: Duration,
+) -> io::Result<(Option<(ExitStatus, Vec<u8>)>, Vec<u8>)> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = Arc::new(Mutex::new(vec![]));
+    let mut draining = tokio::spawn(drain(
+        child.stderr.take().expect("stderr is piped"),
+        stderr.clone(),
+    ));
+    let finished = timeout(limit, async {
+        let mut out = vec![];
+        stdout.read_to_end(&mut out).await?;
+        Ok::<_, io::Error>((child.wait().await?, out))
+    })
+    .await;
+    let finished = match finished {
+        Ok(finished) => Some(finished?),
+        Err(_) => {
+            child.kill().await?;
+            None
+        }
+    };
+    if timeout(DRAIN, &mut draining).await.is_err() {
+        draining.abort();
+    }
+    let stderr = std::mem::take(&mut *stderr.lock().unwrap());
+    Ok((finished, stderr))
+}
+
+/// Reads a pipe to the end, keeping what was read so far in `buffer`
+async fn drain(mut pipe: impl AsyncRead + Unpin, buffer: Arc<Mutex<Vec<u8>>>) -> io::Result<()> {
+    let mut chunk = [0; 4096];
+    loop {
+        let read = pipe.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buffer.lock().unwrap().extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::Source;
+    use serde_json::{json, Value as JsonValue};
+
+    fn exec(command: &str, args: &[&str]) -> Exec {
+        Exec {
+            command: command.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            env: HashMap::new(),
+            timeout: default_timeout(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stdout_is_parsed_as_json_or_yaml() {
+        for (config, stdout) in [
+            (json!({}), r#"[{"name": "a"}]"#),
+            (json!({"deserialize_as": "yaml"}), "- name: a\n"),
+        ] {
+            let mut config = config;
+            config["command"] = json!("printf");
+            config["args"] = json!(["%s", stdout]);
+            let source: Source =
+                serde_json::from_value(json!({"type": "exec", "config": config})).unwrap();
+            let data: JsonValue = serde_json::from_str(&source.get().unwrap()).unwrap();
+            assert_eq!(data, json!([{"name": "a"}]));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn non_zero_exits_fail() {
+        let err = exec("sh", &["-c", "echo broken >&2; exit 3"])
+            .run()
+            .unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn stderr_is_kept_when_a_command_times_out() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo starting >&2; sleep 5"]);
+        let (finished, stderr) = output(command, Duration::from_millis(200)).await.unwrap();
+        assert!(finished.is_none());
+        assert_eq!(stderr, b"starting\n");
+    }
+}
@@ -0,0 +1,252 @@
+use minijinja::{Environment, Value as JinjaValue};
+use serde::{de, Deserialize};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use tracing::warn;
+
+/// A step applied to the instances of a source before they are packaged
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transform {
+    /// Moves a field, given as a dotted path, to another path
+    Rename { from: String, to: String },
+    /// Replaces the list of instances with the result of a JMESPath expression
+    Jmespath {
+        #[serde(deserialize_with = "deserialize_expression")]
+        expression: jmespath::Expression<'static>,
+    },
+    /// Keeps instances for which a template expression is truthy, e.g. `name != "legacy"`
+    Filter { predicate: Predicate },
+    /// Adds static fields to every instance, overwriting existing values
+    Labels { labels: Map<String, JsonValue> },
+    /// Drops instances that repeat the value at a dotted path, keeping the first
+    Dedupe { key: String },
+}
+
+/// A template expression, compiled when the config is loaded
+#[derive(Clone)]
+pub struct Predicate {
+    expression: String,
+    env: Arc<Environment<'static>>,
+}
+
+impl Predicate {
+    fn new(expression: String) -> Result<Self, minijinja::Error> {
+        // Expressions borrow their source, so the predicate is kept as a template instead
+        let mut env = Environment::new();
+        env.add_template_owned(
+            "predicate",
+            format!("{{% if {expression} %}}true{{% endif %}}"),
+        )?;
+        Ok(Self {
+            expression,
+            env: Arc::new(env),
+        })
+    }
+
+    fn is_true(&self, instance: &JsonValue) -> Result<bool, minijinja::Error> {
+        let template = self.env.get_template("predicate")?;
+        Ok(template.render(JinjaValue::from_serializable(instance))? == "true")
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Predicate").field(&self.expression).finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for Predicate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let expression = String::deserialize(deserializer)?;
+        Predicate::new(expression).map_err(de::Error::custom)
+    }
+}
+
+/// Compiles a JMESPath expression when the config is loaded
+fn deserialize_expression<'de, D>(
+    deserializer: D,
+) -> Result<jmespath::Expression<'static>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let expression = String::deserialize(deserializer)?;
+    jmespath::compile(&expression).map_err(de::Error::custom)
+}
+
+fn lookup<'a>(value: &'a JsonValue, path: &str) -> Option<&'a JsonValue> {
+    path.split('.').try_fold(value, |v, segment| v.get(segment))
+}
+
+fn lookup_mut<'a>(value: &'a mut JsonValue, path: &str) -> Option<&'a mut JsonValue> {
+    path.split('.')
+        .try_fold(value, |v, segment| v.get_mut(segment))
+}
+
+fn remove(value: &mut JsonValue, path: &str) -> Option<JsonValue> {
+    let (parent, last) = match path.rsplit_once('.') {
+        Some((parent, last)) => (lookup_mut(value, parent)?, last),
+        None => (value, path),
+    };
+    parent.as_object_mut()?.remove(last)
+}
+
+/// Inserts at a dotted path, creating objects along the way. Gives the value back
+/// if the path runs into a value that isn't an object.
+fn insert(value: &mut JsonValue, path: &str, new: JsonValue) -> Result<(), JsonValue> {
+    let mut current = value;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        let Some(map) = current.as_object_mut() else {
+            return Err(new);
+        };
+        if segments.peek().is_none() {
+            map.insert(segment.to_string(), new);
+            return Ok(());
+        }
+        current = map
+            .entry(segment)
+            .or_insert_with(|| JsonValue::Object(Map::new()));
+    }
+    Ok(())
+}
+
+impl Transform {
+    pub fn apply(&self, instances: Vec<JsonValue>) -> anyhow::Result<Vec<JsonValue>> {
+        match self {
+            Transform::Rename { from, to } => Ok(instances
+                .into_iter()
+                .map(|mut instance| {
+                    if let Some(value) = remove(&mut instance, from) {
+                        // Nothing is created when the insert fails, so `from` can be restored
+                        if let Err(value) = insert(&mut instance, to, value) {
+                            _ = insert(&mut instance, from, value);
+                            warn!("Not renaming {from} to {to}, a parent of {to} is not an object");
+                        }
+                    }
+                    instance
+                })
+                .collect()),
+            Transform::Jmespath { expression } => {
+                let result = expression.search(JsonValue::Array(instances))?;
+                match serde_json::to_value(&*result)? {
+                    JsonValue::Array(instances) => Ok(instances),
+                    JsonValue::Null => Ok(vec![]),
+                    other => Ok(vec![other]),
+                }
+            }
+            Transform::Filter { predicate } => {
+                let mut ret = vec![];
+                for instance in instances {
+                    if predicate.is_true(&instance)? {
+                        ret.push(instance);
+                    }
+                }
+                Ok(ret)
+            }
+            Transform::Labels { labels } => Ok(instances
+                .into_iter()
+                .map(|mut instance| {
+                    if let Some(map) = instance.as_object_mut() {
+                        map.extend(labels.clone());
+                    }
+                    instance
+                })
+                .collect()),
+            Transform::Dedupe { key } => {
+                let mut seen = HashSet::new();
+                Ok(instances
+                    .into_iter()
+                    .filter(|instance| match lookup(instance, key) {
+                        Some(value) => seen.insert(value.to_string()),
+                        None => true,
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transform(config: JsonValue) -> Transform {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn rename_moves_nested_fields() {
+        let rename =
+            transform(json!({"type": "rename", "from": "meta.zone", "to": "locality.zone"}));
+        let renamed = rename
+            .apply(vec![
+                json!({"meta": {"zone": "a", "rack": 1}}),
+                json!({"name": "b"}),
+            ])
+            .unwrap();
+        assert_eq!(
+            renamed,
+            vec![
+                json!({"meta": {"rack": 1}, "locality": {"zone": "a"}}),
+                json!({"name": "b"})
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_into_a_scalar_leaves_the_instance_unchanged() {
+        let rename = transform(json!({"type": "rename", "from": "zone", "to": "meta.zone"}));
+        let renamed = rename
+            .apply(vec![
+                json!({"zone": "a", "meta": "b"}),
+                json!({"zone": "c", "meta": {}}),
+            ])
+            .unwrap();
+        assert_eq!(
+            renamed,
+            vec![
+                json!({"zone": "a", "meta": "b"}),
+                json!({"meta": {"zone": "c"}})
+            ]
+        );
+    }
+
+    #[test]
+    fn jmespath_syntax_errors_fail_at_load() {
+        let jmespath =
+            serde_json::from_value::<Transform>(json!({"type": "jmespath", "expression": "[?"}));
+        assert!(jmespath.is_err());
+        let jmespath = transform(json!({"type": "jmespath", "expression": "[?port].name"}));
+        let names = jmespath
+            .apply(vec![json!({"name": "a", "port": 80}), json!({"name": "b"})])
+            .unwrap();
+        assert_eq!(names, vec![json!("a")]);
+    }
+
+    #[test]
+    fn filter_keeps_truthy_instances() {
+        let filter = transform(json!({"type": "filter", "predicate": "name != 'legacy' and port"}));
+        let kept = filter
+            .apply(vec![
+                json!({"name": "legacy", "port": 80}),
+                json!({"name": "web", "port": 80}),
+                json!({"name": "web"}),
+            ])
+            .unwrap();
+        assert_eq!(kept, vec![json!({"name": "web", "port": 80})]);
+    }
+
+    #[test]
+    fn filter_syntax_errors_fail_at_load() {
+        let filter =
+            serde_json::from_value::<Transform>(json!({"type": "filter", "predicate": "name =="}));
+        assert!(filter.is_err());
+    }
+}
@@ -1,19 +1,230 @@
 use crate::context::DeserializeAs;
+use crate::envoy_types::DiscoveryRequest;
+use crate::git::resolve;
+use crate::matching::{GlobPattern, NodeKey};
 use minijinja::Value as JinjaValue;
 use pyo3::prelude::*;
-use serde::Deserialize;
+use regex::Regex;
+use semver::{Comparator, Op, Version, VersionReq};
+use serde::{de, Deserialize};
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing::warn;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct XdsTemplate {
     path: PathBuf,
-    envoy_version: String,
-    resource_type: String,
+    pub envoy_version: VersionMatch,
+    pub resource_type: String,
     #[serde(default)]
     pub deserialize_as: DeserializeAs,
     pub call_python: Option<bool>,
+    /// When several templates match a request, the highest priority wins
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub selector: TemplateSelector,
+}
+
+/// The Envoy versions a template applies to.
+///
+/// Accepts `default`, a version prefix such as `1.25`, or a semver range such as `>=1.24, <1.27`.
+#[derive(Clone, Debug)]
+pub enum VersionMatch {
+    Default,
+    Range { raw: String, req: VersionReq },
+}
+
+impl FromStr for VersionMatch {
+    type Err = semver::Error;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if raw == "default" {
+            return Ok(VersionMatch::Default);
+        }
+        // A bare version is a prefix, which semver would otherwise read as a caret range
+        static BARE: OnceLock<Regex> = OnceLock::new();
+        let bare = BARE.get_or_init(|| Regex::new(r"^\d+(\.\d+){0,2}$").unwrap());
+        let req = if bare.is_match(raw) {
+            VersionReq::parse(&format!("={raw}"))
+        } else {
+            VersionReq::parse(raw)
+        }?;
+        Ok(VersionMatch::Range {
+            raw: raw.to_string(),
+            req,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionMatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for VersionMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionMatch::Default => f.write_str("default"),
+            VersionMatch::Range { raw, .. } => f.write_str(raw),
+        }
+    }
+}
+
+/// Bounds of a comparator as `[lower, upper)`, with versions flattened to integers
+fn bounds(comparator: &Comparator) -> (u64, u64) {
+    // Saturating, as versions in ranges are arbitrary u64s
+    let flatten = |major: u64, minor: u64, patch: u64| {
+        major
+            .saturating_mul(1_000_000)
+            .saturating_add(minor.min(999) * 1_000 + patch.min(999))
+    };
+    let major = comparator.major;
+    let minor = comparator.minor.unwrap_or(0);
+    let patch = comparator.patch.unwrap_or(0);
+    let at = flatten(major, minor, patch);
+    // The first version beyond everything the comparator's precision covers
+    let after = match (comparator.minor, comparator.patch) {
+        (None, _) => flatten(major.saturating_add(1), 0, 0),
+        (Some(_), None) => flatten(major, minor.saturating_add(1), 0),
+        (Some(_), Some(_)) => at.saturating_add(1),
+    };
+    match comparator.op {
+        Op::Exact | Op::Wildcard => (at, after),
+        Op::Greater => (after, u64::MAX),
+        Op::GreaterEq => (at, u64::MAX),
+        Op::Less => (0, at),
+        Op::LessEq => (0, after),
+        Op::Tilde if comparator.minor.is_some() => (at, flatten(major, minor.saturating_add(1), 0)),
+        Op::Tilde => (at, flatten(major.saturating_add(1), 0, 0)),
+        Op::Caret if major > 0 || comparator.minor.is_none() => {
+            (at, flatten(major.saturating_add(1), 0, 0))
+        }
+        // `^0.0.3` only covers 0.0.3, where `^0.0` covers 0.0.x
+        Op::Caret if minor == 0 && comparator.patch.is_some() => (at, after),
+        Op::Caret => (at, flatten(0, minor.saturating_add(1), 0)),
+        _ => (0, u64::MAX),
+    }
+}
+
+impl VersionMatch {
+    pub fn matches(&self, version: &Version) -> bool {
+        match self {
+            VersionMatch::Default => true,
+            VersionMatch::Range { req, .. } => req.matches(version),
+        }
+    }
+
+    /// How many versions the match covers, where fewer is more specific.
+    /// The default template covers everything and is the least specific.
+    pub fn span(&self) -> Option<u64> {
+        match self {
+            VersionMatch::Default => None,
+            VersionMatch::Range { req, .. } => {
+                let (lower, upper) = req
+                    .comparators
+                    .iter()
+                    .map(bounds)
+                    .fold((0, u64::MAX), |(lower, upper), (l, u)| {
+                        (lower.max(l), upper.min(u))
+                    });
+                Some(upper.saturating_sub(lower))
+            }
+        }
+    }
+}
+
+/// Restricts a template to requests whose node and host match every pattern
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct TemplateSelector {
+    /// Glob patterns keyed by node attribute, e.g. `cluster` or `metadata.role`
+    #[serde(default)]
+    node: HashMap<NodeKey, GlobPattern>,
+    host_header: Option<GlobPattern>,
+}
+
+impl TemplateSelector {
+    pub fn is_empty(&self) -> bool {
+        self.node.is_empty() && self.host_header.is_none()
+    }
+
+    pub fn matches(&self, request: &DiscoveryRequest, host_header: &str) -> bool {
+        let node_matches = self
+            .node
+            .iter()
+            .all(|(key, pattern)| match key.value(request) {
+                Some(value) => pattern.matches(&value),
+                None => false,
+            });
+        let host_matches = self
+            .host_header
+            .as_ref()
+            .is_none_or(|pattern| pattern.matches(host_header));
+        node_matches && host_matches
+    }
+}
+
+/// Template file extensions, most specific first, with how templates using them are rendered
+const EXTENSIONS: &[(&str, DeserializeAs, bool)] = &[
+    (".yaml.jinja2", DeserializeAs::Yaml, false),
+    (".json.jinja2", DeserializeAs::Json, false),
+    (".jinja2", DeserializeAs::Json, false),
+    (".py", DeserializeAs::Json, true),
+];
+
+/// Finds the templates laid out as `<dir>/<envoy version>/<resource type>.<ext>`,
+/// e.g. `xds_templates/1.25/clusters.yaml.jinja2` or `xds_templates/default/routes.py`
+pub fn discover(dir: &Path) -> anyhow::Result<Vec<XdsTemplate>> {
+    let mut templates = vec![];
+    for version_dir in std::fs::read_dir(resolve(dir))? {
+        let version_dir = version_dir?;
+        if !version_dir.file_type()?.is_dir() {
+            continue;
+        }
+        let version = version_dir.file_name().to_string_lossy().to_string();
+        // Other directories, e.g. for macros shared between versions, are left alone
+        let envoy_version: VersionMatch = match version.parse() {
+            Ok(envoy_version) => envoy_version,
+            Err(e) => {
+                warn!("Skipping {}: {e}", version_dir.path().display());
+                continue;
+            }
+        };
+        for file in std::fs::read_dir(version_dir.path())? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            let Some((resource_type, deserialize_as, call_python)) =
+                EXTENSIONS
+                    .iter()
+                    .find_map(|(extension, deserialize_as, call_python)| {
+                        Some((name.strip_suffix(extension)?, deserialize_as, call_python))
+                    })
+            else {
+                continue;
+            };
+            templates.push(XdsTemplate {
+                // Kept relative to `dir`, so that it still resolves after a git checkout swaps
+                path: dir.join(&version).join(&name),
+                envoy_version: envoy_version.clone(),
+                resource_type: resource_type.to_string(),
+                deserialize_as: deserialize_as.clone(),
+                call_python: Some(*call_python),
+                priority: 0,
+                selector: TemplateSelector::default(),
+            });
+        }
+    }
+    // Directory order varies between filesystems
+    templates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(templates)
 }
 
 const PY_BOILETPLATE: &str = r#"
@@ -26,12 +237,28 @@ def main(kw):
 "#;
 
 impl XdsTemplate {
+    /// Whether this template wins over `other` when both match a request
+    pub fn preferred_over(&self, other: &XdsTemplate) -> bool {
+        if self.priority != other.priority {
+            return self.priority > other.priority;
+        }
+        match (self.envoy_version.span(), other.envoy_version.span()) {
+            (Some(a), Some(b)) => a < b,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
     pub fn name(&self) -> String {
         format!("{}/{}", self.envoy_version, self.resource_type)
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn source(&self) -> std::io::Result<String> {
-        let file = std::fs::File::open(&self.path)?;
+        let file = std::fs::File::open(resolve(&self.path))?;
         let mut reader = BufReader::new(file);
         let mut content = String::new();
         reader.read_to_string(&mut content)?;
@@ -42,14 +269,95 @@ impl XdsTemplate {
         Python::with_gil(|py| -> anyhow::Result<String> {
             let module = PyModule::from_code(
                 py,
-                &format!("{}\n{}", PY_BOILETPLATE, &self.source().unwrap()),
+                &format!("{}\n{}", PY_BOILETPLATE, &self.source()?),
                 &self.path.to_string_lossy(),
                 "template",
             )?;
             Ok(module
                 .getattr("main")?
-                .call1((serde_json::to_string(&kwargs).unwrap(),))?
+                .call1((serde_json::to_string(&kwargs)?,))?
                 .extract::<String>()?)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version_match(raw: &str) -> VersionMatch {
+        raw.parse().unwrap()
+    }
+
+    fn matches(raw: &str, version: &str) -> bool {
+        version_match(raw).matches(&Version::parse(version).unwrap())
+    }
+
+    #[test]
+    fn discover_skips_directories_that_arent_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "default/clusters.yaml.jinja2",
+            "1.25/routes.py",
+            "macros/common.jinja2",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let found: Vec<String> = discover(dir.path())
+            .unwrap()
+            .iter()
+            .map(|t| t.resource_type.clone())
+            .collect();
+        assert_eq!(found, ["routes", "clusters"]);
+    }
+
+    #[test]
+    fn bare_versions_are_prefixes() {
+        assert!(matches("1.25", "1.25.0"));
+        assert!(matches("1.25", "1.25.9"));
+        assert!(!matches("1.25", "1.26.0"));
+        assert!(matches("1", "1.30.2"));
+        assert!(matches("1.25.3", "1.25.3"));
+        assert!(!matches("1.25.3", "1.25.4"));
+    }
+
+    #[test]
+    fn ranges_use_semver() {
+        assert!(matches(">=1.24, <1.27", "1.26.1"));
+        assert!(!matches(">=1.24, <1.27", "1.27.0"));
+        assert!(matches("~1.25", "1.25.4"));
+        assert!(matches("default", "0.1.0"));
+        assert!("1.x.y.z".parse::<VersionMatch>().is_err());
+    }
+
+    #[test]
+    fn narrower_ranges_span_less() {
+        let span = |raw: &str| version_match(raw).span().unwrap();
+        assert_eq!(span("1.25"), 1_000);
+        assert_eq!(span("1.25.3"), 1);
+        assert_eq!(span(">=1.24, <1.27"), 3_000);
+        assert!(span("1") > span(">=1.24, <1.27"));
+        assert_eq!(span("^0.0.3"), 1);
+        assert_eq!(span("^0.0"), 1_000);
+        assert_eq!(span("^0.2.3"), 997);
+        assert_eq!(version_match("default").span(), None);
+    }
+
+    #[test]
+    fn bounds_saturate_on_huge_versions() {
+        let max = u64::MAX.to_string();
+        for raw in [
+            max.clone(),
+            format!("1.{max}"),
+            format!("1.2.{max}"),
+            format!("~{max}.{max}"),
+            format!("^0.{max}"),
+            format!(">{max}.{max}.{max}"),
+            format!("<={max}"),
+        ] {
+            version_match(&raw).span();
+        }
+    }
+}
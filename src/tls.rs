@@ -0,0 +1,269 @@
+use crate::auth::PeerIdentity;
+use crate::listeners::{accept_failed, close_connections, serve_connection};
+use axum::extract::Extension;
+use axum::Router;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslRef, SslVerifyMode};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::sync::watch::{self, Receiver};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_openssl::SslStream;
+use tracing::{debug, info, warn};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// When set, clients must present a certificate signed by one of these CAs
+    pub client_ca: Option<PathBuf>,
+    /// How often the files are checked for changes
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    fn acceptor(&self) -> anyhow::Result<SslAcceptor> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        builder.set_private_key_file(&self.key, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(&self.cert)?;
+        builder.check_private_key()?;
+        if let Some(ca) = &self.client_ca {
+            builder.set_ca_file(ca)?;
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(builder.build())
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Builds an acceptor, and rebuilds it whenever the certificate files change
+    pub fn watch(self) -> anyhow::Result<Receiver<Arc<SslAcceptor>>> {
+        let (tx, rx) = watch::channel(Arc::new(self.acceptor()?));
+        let mut last = self.modified();
+        tokio::spawn(async move {
+            loop {
+                sleep(self.reload_interval).await;
+                let modified = self.modified();
+                if modified == last {
+                    continue;
+                }
+                // Keep serving the old certificate if the new files are half-written
+                match self.acceptor() {
+                    Ok(acceptor) => {
+                        info!(cert = %self.cert.display(), "Reloaded TLS certificate");
+                        last = modified;
+                        _ = tx.send(Arc::new(acceptor));
+                    }
+                    Err(e) => {
+                        warn!(cert = %self.cert.display(), "Could not reload TLS certificate: {e}")
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// The SANs of a verified client certificate
+pub fn peer_identity(ssl: &SslRef) -> Option<PeerIdentity> {
+    let cert = ssl.peer_certificate()?;
+    let sans = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    name.uri()
+                        .map(|uri| format!("URI:{uri}"))
+                        .or_else(|| name.dnsname().map(|dns| format!("DNS:{dns}")))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(PeerIdentity { sans })
+}
+
+/// Serves the app over TLS, giving handlers the peer identity of each connection
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: Receiver<Arc<SslAcceptor>>,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    grace_period: Duration,
+) -> anyhow::Result<()> {
+    let (closing_tx, closing) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    accept_failed(e).await;
+                    continue;
+                }
+            },
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
+        let acceptor = acceptor.borrow().clone();
+        let (app, closing) = (app.clone(), closing.clone());
+        connections.spawn(async move {
+            let mut stream =
+                match Ssl::new(acceptor.context()).and_then(|ssl| SslStream::new(ssl, stream)) {
+                    Ok(stream) => stream,
+                    Err(e) => return warn!(%remote, "Could not set up TLS: {e}"),
+                };
+            // Clients that never finish the handshake would otherwise hold the connection open
+            match timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return debug!(%remote, "TLS handshake failed: {e}"),
+                Err(_) => return debug!(%remote, "TLS handshake timed out"),
+            }
+            let app = match peer_identity(stream.ssl()) {
+                Some(peer) => app.layer(Extension(peer)),
+                None => app,
+            };
+            if let Err(e) = serve_connection(stream, app, closing).await {
+                debug!(%remote, "Connection closed with an error: {e}");
+            }
+        });
+    }
+    // In-flight requests finish before the listener counts as stopped
+    close_connections(closing_tx, connections, grace_period).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslConnector;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Writes a self-signed certificate for `name` and its key to `<name>.pem` and `<name>.key`
+    fn self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let (cert_path, key_path) = (
+            dir.join(format!("{name}.pem")),
+            dir.join(format!("{name}.key")),
+        );
+        std::fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn config(cert: PathBuf, key: PathBuf, client_ca: Option<PathBuf>) -> TlsConfig {
+        TlsConfig {
+            cert,
+            key,
+            client_ca,
+            reload_interval: Duration::from_millis(10),
+        }
+    }
+
+    fn common_name(acceptor: &SslAcceptor) -> String {
+        let cert = acceptor.context().certificate().unwrap();
+        let entry = cert.subject_name().entries().next().unwrap();
+        entry.data().to_string().unwrap()
+    }
+
+    /// Makes a request over TLS, presenting the client certificate if one is given
+    async fn request(
+        addr: std::net::SocketAddr,
+        client: Option<&(PathBuf, PathBuf)>,
+    ) -> anyhow::Result<String> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((cert, key)) = client {
+            connector.set_certificate_chain_file(cert)?;
+            connector.set_private_key_file(key, SslFiletype::PEM)?;
+        }
+        let ssl = connector.build().configure()?.into_ssl("localhost")?;
+        let mut stream = SslStream::new(ssl, TcpStream::connect(addr).await?)?;
+        Pin::new(&mut stream).connect().await?;
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded_when_the_files_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed(dir.path(), "first");
+        let mut acceptor = config(cert.clone(), key.clone(), None).watch().unwrap();
+        assert_eq!(common_name(&acceptor.borrow()), "first");
+
+        // A half-written certificate keeps the old one in place
+        std::fs::write(&cert, "-----BEGIN CERTIFICATE-----").unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(!acceptor.has_changed().unwrap());
+
+        let (second_cert, second_key) = self_signed(dir.path(), "second");
+        std::fs::rename(second_key, &key).unwrap();
+        std::fs::rename(second_cert, &cert).unwrap();
+        timeout(Duration::from_secs(5), acceptor.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(common_name(&acceptor.borrow()), "second");
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_required_certificate_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = self_signed(dir.path(), "server");
+        let client = self_signed(dir.path(), "client");
+        let acceptor = config(cert, key, Some(client.0.clone())).watch().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(serve(
+            listener,
+            acceptor,
+            app,
+            std::future::pending(),
+            Duration::from_secs(1),
+        ));
+
+        let response = request(addr, Some(&client)).await.unwrap();
+        assert!(response.ends_with("ok"), "{response}");
+        // The server's rejection can arrive after the client finishes its side of the handshake
+        let rejected = request(addr, None).await;
+        assert!(rejected.is_err() || rejected.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,465 @@
+use crate::config::{config_files, load, Settings};
+use crate::git::resolve;
+use crate::sources::Source;
+use crate::templates::XdsTemplate;
+use futures::future::join_all;
+use minijinja::Environment;
+use pyo3::prelude::*;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+/// Source and context types that only exist when a cargo feature is enabled,
+/// as (section, type, feature, enabled)
+const FEATURE_GATED: &[(&str, &str, &str, bool)] = &[
+    ("sources", "dns", "dns", cfg!(feature = "dns")),
+    ("template_context", "s3", "s3", cfg!(feature = "s3")),
+    ("secrets", "s3", "s3", cfg!(feature = "s3")),
+];
+
+/// Something in the config that would fail at runtime
+#[derive(Debug)]
+pub struct Problem {
+    /// A file and line, or the config key the problem was found under
+    pub location: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn problem(location: impl Into<String>, message: impl Into<String>) -> Problem {
+    Problem {
+        location: location.into(),
+        message: message.into(),
+    }
+}
+
+/// Where each config key is set, searching the files that override the others first
+struct Locator(Vec<(String, HashMap<String, usize>)>);
+
+impl Locator {
+    fn new(config_path: &str) -> Self {
+        let files = config_files(config_path).unwrap_or_default();
+        Locator(
+            files
+                .into_iter()
+                .rev()
+                .filter_map(|file| {
+                    let content = std::fs::read_to_string(&file).ok()?;
+                    Some((file, key_lines(&content)))
+                })
+                .collect(),
+        )
+    }
+
+    /// A problem at the file and line `key` is set on, or under the key when it can't be found
+    fn problem(&self, key: &str, message: impl Into<String>) -> Problem {
+        let found = self
+            .0
+            .iter()
+            .find_map(|(file, lines)| Some(format!("{file}:{}", lines.get(key)?)));
+        match found {
+            Some(location) => problem(location, format!("{key}: {}", message.into())),
+            None => problem(key, message),
+        }
+    }
+}
+
+/// A mapping or sequence being walked, under the key path it is found at
+enum Frame {
+    Map { path: String, key: Option<String> },
+    Seq { path: String, index: usize },
+}
+
+/// Records the line of every key, as paths like `sources.items[0].config`
+#[derive(Default)]
+struct KeyLines {
+    frames: Vec<Frame>,
+    lines: HashMap<String, usize>,
+}
+
+impl KeyLines {
+    /// The path of a value starting at `line`, or None when it is a mapping key
+    fn value(&mut self, line: usize) -> Option<String> {
+        match self.frames.last_mut() {
+            None => Some(String::new()),
+            Some(Frame::Map { key: None, .. }) => None,
+            Some(Frame::Map { path, key }) => key.take().map(|key| join(path, &key)),
+            Some(Frame::Seq { path, index }) => {
+                let item = format!("{path}[{index}]");
+                *index += 1;
+                self.lines.entry(item.clone()).or_insert(line);
+                Some(item)
+            }
+        }
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        path => format!("{path}.{key}"),
+    }
+}
+
+impl MarkedEventReceiver for KeyLines {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let line = mark.line();
+        match event {
+            Event::Scalar(value, ..) => match self.frames.last_mut() {
+                Some(Frame::Map {
+                    path,
+                    key: key @ None,
+                }) => {
+                    self.lines.entry(join(path, &value)).or_insert(line);
+                    *key = Some(value);
+                }
+                _ => {
+                    self.value(line);
+                }
+            },
+            Event::MappingStart(_) => {
+                let path = self.value(line).unwrap_or_default();
+                self.frames.push(Frame::Map { path, key: None });
+            }
+            Event::SequenceStart(_) => {
+                let path = self.value(line).unwrap_or_default();
+                self.frames.push(Frame::Seq { path, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+            }
+            Event::Alias(_) => {
+                self.value(line);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The line each key in a YAML file is set on, for as much of the file as parses
+fn key_lines(content: &str) -> HashMap<String, usize> {
+    let mut receiver = KeyLines::default();
+    let _ = Parser::new(content.chars()).load(&mut receiver, false);
+    receiver.lines
+}
+
+/// Checks the config without serving it, returning every problem found.
+/// Context items are only loaded with `load_context`, since loading makes requests and runs commands.
+pub async fn validate(config_path: &str, load_context: bool) -> Vec<Problem> {
+    let config = match load(config_path) {
+        Ok(config) => config,
+        Err(e) => return vec![problem(config_path, e.to_string())],
+    };
+    let locator = Locator::new(config_path);
+    let mut problems = vec![];
+    if let Ok(raw) = config.clone().try_deserialize::<JsonValue>() {
+        problems.extend(check_features(&locator, &raw));
+    }
+    match config.try_deserialize::<Settings>() {
+        Ok(settings) => {
+            if let Err(e) = settings.check() {
+                // Most problems name the key they were found under
+                let problem = match e.split_once(": ") {
+                    Some((key, message)) => locator.problem(key, message),
+                    None => problem(config_path, e),
+                };
+                problems.push(problem);
+            }
+            // Relative paths are checked against a checkout of the repository when there is one
+            let checkout = settings.git.as_ref().map(|git| git.checkout_private());
+            if let Some(Err(e)) = &checkout {
+                problems.push(locator.problem("git", e.to_string()));
+                return problems;
+            }
+            problems.extend(check_settings(&locator, &settings));
+            problems.extend(check_context(&locator, &settings));
+            if load_context {
+                problems.extend(load_context_items(&locator, &settings).await);
+            }
+        }
+        Err(e) => problems.push(problem(config_path, e.to_string())),
+    }
+    problems
+}
+
+/// Types that fail to deserialize because their feature is disabled, reported by name
+fn check_features(locator: &Locator, raw: &JsonValue) -> Vec<Problem> {
+    let mut used: Vec<(String, String)> = vec![];
+    if let Some(items) = raw.pointer("/sources/items").and_then(|i| i.as_array()) {
+        for (i, item) in items.iter().enumerate() {
+            if let Some(kind) = item.get("type").and_then(|t| t.as_str()) {
+                used.push((format!("sources.items[{i}]"), kind.to_string()));
+            }
+        }
+    }
+    if let Some(items) = raw
+        .pointer("/template_context/items")
+        .and_then(|i| i.as_object())
+    {
+        for (name, item) in items.iter() {
+            if let Some(data_source) = item.get("data_source").and_then(|d| d.as_object()) {
+                for kind in data_source.keys() {
+                    used.push((format!("template_context.items.{name}"), kind.clone()));
+                }
+            }
+        }
+    }
+    if let Some(items) = raw.pointer("/secrets/items").and_then(|i| i.as_array()) {
+        for (i, item) in items.iter().enumerate() {
+            // Secrets nest a source under each field, e.g. `tls_certificate.private_key.file`
+            let sources = item
+                .as_object()
+                .into_iter()
+                .flat_map(|item| item.values())
+                .filter_map(|kind| kind.as_object())
+                .flat_map(|fields| fields.values())
+                .filter_map(|source| source.as_object());
+            for source in sources {
+                for kind in source.keys() {
+                    used.push((format!("secrets.items[{i}]"), kind.clone()));
+                }
+            }
+        }
+    }
+    used.into_iter()
+        .filter_map(|(key, kind)| {
+            let (_, _, feature, enabled) = FEATURE_GATED
+                .iter()
+                .find(|(section, gated, _, _)| key.starts_with(section) && *gated == kind)?;
+            (!enabled).then(|| {
+                locator.problem(
+                    &key,
+                    format!("type `{kind}` requires building with the `{feature}` feature"),
+                )
+            })
+        })
+        .collect()
+}
+
+fn check_settings(locator: &Locator, settings: &Settings) -> Vec<Problem> {
+    let mut problems = vec![];
+    let templates = match settings.all_templates() {
+        Ok(templates) => templates,
+        Err(e) => {
+            problems.push(locator.problem("template_dirs", e.to_string()));
+            settings.templates.clone()
+        }
+    };
+    let mut seen: HashMap<(String, i32), String> = HashMap::new();
+    for template in templates.iter() {
+        let key = template.path().display().to_string();
+        problems.extend(check_template(template));
+        // Templates with selectors may share a name, since they match different nodes
+        if !template.selector.is_empty() {
+            continue;
+        }
+        let name = (template.name(), template.priority);
+        if let Some(first) = seen.get(&name) {
+            problems.push(problem(
+                key,
+                format!(
+                    "duplicate template {}, already defined by {first} and never selected",
+                    template.name()
+                ),
+            ));
+        } else {
+            seen.insert(name, key);
+        }
+    }
+
+    if let Some(sources) = &settings.sources {
+        for (i, item) in sources.items.iter().enumerate() {
+            let key = format!("sources.items[{i}]");
+            match &item.source {
+                Source::PythonScript { path } => match std::fs::read_to_string(resolve(path)) {
+                    Ok(code) => problems.extend(check_python(&code, path, "main")),
+                    Err(e) => {
+                        problems.push(locator.problem(&key, format!("{}: {e}", path.display())))
+                    }
+                },
+                Source::PythonInline { code } => {
+                    problems.extend(check_python(code, Path::new(&key), "main"))
+                }
+                Source::File { path } if !resolve(path).exists() => problems
+                    .push(locator.problem(&key, format!("{} does not exist", path.display()))),
+                _ => {}
+            }
+        }
+    }
+    problems
+}
+
+/// Checks what can be known about each context item without loading it
+fn check_context(locator: &Locator, settings: &Settings) -> Vec<Problem> {
+    let Some(config) = &settings.template_context else {
+        return vec![];
+    };
+    let items: BTreeMap<_, _> = config.items.iter().collect();
+    items
+        .into_iter()
+        .filter_map(|(name, item)| {
+            let e = item.check().err()?;
+            Some(locator.problem(&format!("template_context.items.{name}"), e))
+        })
+        .collect()
+}
+
+/// Loads every context item once, as the server would at startup
+async fn load_context_items(locator: &Locator, settings: &Settings) -> Vec<Problem> {
+    let Some(config) = &settings.template_context else {
+        return vec![];
+    };
+    let items: BTreeMap<_, _> = config.items.iter().collect();
+    let loaded = join_all(items.values().map(|item| item.load_once())).await;
+    items
+        .keys()
+        .zip(loaded)
+        .filter_map(|(name, result)| {
+            let e = result.err()?;
+            Some(locator.problem(
+                &format!("template_context.items.{name}"),
+                format!("could not load: {e}"),
+            ))
+        })
+        .collect()
+}
+
+fn check_template(template: &XdsTemplate) -> Vec<Problem> {
+    let path = template.path();
+    let source = match template.source() {
+        Ok(source) => source,
+        Err(e) => return vec![problem(path.display().to_string(), e.to_string())],
+    };
+    if template.call_python == Some(true) {
+        return check_python(&source, path, "call");
+    }
+    let env = Environment::new();
+    match env.template_from_str(&source) {
+        Ok(_) => vec![],
+        Err(e) => vec![problem(
+            location(path, e.line()),
+            e.detail().map_or_else(|| e.to_string(), |d| d.to_string()),
+        )],
+    }
+}
+
+/// Lists the names a module binds at its top level, without running it
+const TOP_LEVEL_NAMES: &str = r#"
+import ast
+
+def top_level_names(code, filename):
+    names = []
+    for node in ast.parse(code, filename).body:
+        if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+            names.append(node.name)
+        elif isinstance(node, (ast.Assign, ast.AnnAssign)):
+            targets = node.targets if isinstance(node, ast.Assign) else [node.target]
+            names.extend(t.id for t in targets if isinstance(t, ast.Name))
+        elif isinstance(node, (ast.Import, ast.ImportFrom)):
+            names.extend((a.asname or a.name).split(".")[0] for a in node.names)
+    return names
+"#;
+
+/// Parses Python code without running it, and checks it defines `entrypoint` at the top level
+fn check_python(code: &str, path: &Path, entrypoint: &str) -> Vec<Problem> {
+    let filename = path.to_string_lossy();
+    Python::with_gil(|py| {
+        let names = PyModule::from_code(py, TOP_LEVEL_NAMES, "validate.py", "validate")
+            .and_then(|m| m.getattr("top_level_names"))
+            .and_then(|f| f.call1((code, filename.as_ref())))
+            .and_then(|names| names.extract::<Vec<String>>());
+        match names {
+            Ok(names) if names.iter().any(|n| n == entrypoint) => vec![],
+            Ok(_) => vec![problem(
+                filename.to_string(),
+                format!("does not define `{entrypoint}`"),
+            )],
+            Err(e) => {
+                let line = e
+                    .value(py)
+                    .getattr("lineno")
+                    .and_then(|l| l.extract::<usize>())
+                    .ok();
+                vec![problem(location(path, line), e.to_string())]
+            }
+        }
+    })
+}
+
+fn location(path: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{line}", path.display()),
+        None => path.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn defines(code: &str) -> bool {
+        pyo3::prepare_freethreaded_python();
+        check_python(code, Path::new("test.py"), "main").is_empty()
+    }
+
+    #[test]
+    fn entrypoints_must_be_bound_at_the_top_level() {
+        assert!(defines("def main():\n    return '[]'"));
+        assert!(defines("main = lambda: '[]'"));
+        assert!(defines("from source import fetch as main"));
+        assert!(!defines("def other():\n    return main()"));
+        assert!(!defines("class Source:\n    def main(self):\n        pass"));
+    }
+
+    #[test]
+    fn keys_are_found_on_their_line() {
+        let lines = key_lines(
+            "sources:\n  items:\n    - type: file\n      config:\n        path: a.json\n    - type: inline\n",
+        );
+        assert_eq!(lines["sources"], 1);
+        assert_eq!(lines["sources.items[0]"], 3);
+        assert_eq!(lines["sources.items[0].config.path"], 5);
+        assert_eq!(lines["sources.items[1].type"], 6);
+    }
+
+    #[tokio::test]
+    async fn context_is_checked_without_loading_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("loaded");
+        let config = dir.path().join("sovereign.yaml");
+        let content = json!({
+            "template_context": {"items": {
+                "loads": {"data_source": {"exec": {"command": "touch", "args": [marker]}}},
+                "missing": {"data_source": {"exec": {"command": "/nonexistent/list-services"}}},
+            }},
+        });
+        std::fs::write(&config, serde_yaml::to_string(&content).unwrap()).unwrap();
+        let config = config.to_str().unwrap();
+
+        let problems = validate(config, false).await;
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].location.starts_with(&format!("{config}:")));
+        assert!(problems[0]
+            .message
+            .contains("/nonexistent/list-services not found"));
+        assert!(!marker.exists());
+
+        validate(config, true).await;
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn syntax_errors_report_their_line() {
+        pyo3::prepare_freethreaded_python();
+        let problems = check_python("x = 1\ndef main(:\n", Path::new("test.py"), "main");
+        assert_eq!(problems[0].location, "test.py:2");
+    }
+}